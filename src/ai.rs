@@ -0,0 +1,79 @@
+use serde::{Serialize, Deserialize};
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Time spent flinching after being hurt
+pub const PAIN_TIME: f64 = 0.3;
+// Time an attack takes, from starting the animation to going back to chasing
+pub const ATTACK_TIME: f64 = 0.6;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AiState {
+    Idle, // Standing still, watching
+    Patrol, // Walking in a straight line, turning back at walls
+    Alert, // Just spotted the player, reacting
+    Chase,
+    Attack,
+    Pain,
+    Dead,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttackType {
+    Hitscan, // Shoots from range, needs a clear line of sight
+    Melee, // Has to get within attack_range
+    Projectile, // Launches the entity named by AiTmpl::projectile, needs a clear line of sight
+}
+
+// AI settings in an entity template
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AiTmpl {
+    #[serde(default = "default_start")]
+    pub start: AiState, // Idle or Patrol
+    pub sight_range: f64, // Cells
+    pub reaction_time: f64, // Seconds between spotting the player and chasing
+    pub speed: f64, // Cells per second when chasing, patrolling is half this
+    pub attack: AttackType,
+    pub attack_range: f64,
+    #[serde(default)]
+    pub attack_damage: f64, // Hitscan and melee, projectiles carry their own damage
+    #[serde(default)]
+    pub projectile: String,
+    pub attack_cooldown: f64, // Seconds between attacks
+    // Animation to play in each state. States without one show the standing frame
+    // (pain and dead fall back to the template's pain/death animations)
+    #[serde(default)]
+    pub animations: HashMap<AiState, String>,
+}
+
+fn default_start() -> AiState {
+    AiState::Idle
+}
+
+// Per entity AI state
+#[derive(Debug, Clone)]
+pub struct Ai {
+    pub tmpl: Rc<AiTmpl>, // Shared so tick_ai can hold on to it cheaply while it changes the entity
+    pub state: AiState,
+    pub timer: f64, // Time left in timed states (alert, attack, pain)
+    pub cooldown: f64, // Time until the next attack is allowed
+}
+
+impl Ai {
+    pub fn new(tmpl: &AiTmpl) -> Ai {
+        Ai {
+            tmpl: Rc::new(tmpl.clone()),
+            state: tmpl.start,
+            timer: 0.0,
+            cooldown: 0.0,
+        }
+    }
+
+    // Animation for a state, if the template gives one
+    pub fn animation(&self, state: AiState) -> Option<&String> {
+        self.tmpl.animations.get(&state)
+    }
+}
@@ -15,6 +15,7 @@ pub struct Animation {
     pub name: String,
     pub do_loop: bool,
     pub perm: bool, // If true, animation stops on last frame and stays there (usually death)
+    pub directional: bool, // Rotating sprites pick the column for the viewing angle. Off for e.g. pain frames
     pub curr_frame: usize,
     frames: Vec<AnimationFrame>,
 }
@@ -33,9 +34,15 @@ pub struct AnimationJSON {
     do_loop: bool,
     #[serde(default)]
     perm: bool,
+    #[serde(default = "default_directional")]
+    directional: bool,
     frames: Vec<AnimationFrameJSON>,
 }
 
+fn default_directional() -> bool {
+    true
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AnimationFrameJSON {
     x_pos: u32, // X position of frame in sprite sheet
@@ -99,6 +106,7 @@ impl From<AnimationJSON> for Animation {
             name: json.name,
             do_loop: json.do_loop,
             perm: json.perm,
+            directional: json.directional,
             curr_frame: 0,
             frames: json.frames.iter().map(|f| { AnimationFrame::from(f.clone()) }).collect(),
         }
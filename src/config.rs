@@ -0,0 +1,260 @@
+use serde::Deserialize;
+
+use std::fs::File;
+use std::io::Read;
+use std::error::Error;
+use std::path::Path;
+
+pub const DEFAULT_WIDTH: u32 = 800;
+pub const DEFAULT_HEIGHT: u32 = 600;
+pub const DEFAULT_FOV: f64 = 66.84962236520761; // Degrees, 2 * atan(0.66): the original 0.66 camera plane at 4:3
+const MIN_FOV: f64 = 10.0;
+const MAX_FOV: f64 = 170.0;
+const CONFIG_PATH: &str = "./data/config.json";
+
+// How the rendered frame is fitted into a window of a different size
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScaleMode {
+    // Keep the aspect ratio of the configured resolution, with black bars around the frame
+    Letterbox,
+    // Render at the window's own size so the frame fills it completely
+    Stretch,
+}
+
+impl Default for ScaleMode {
+    fn default() -> ScaleMode {
+        ScaleMode::Letterbox
+    }
+}
+
+// Startup settings. Read from data/config.json if it exists, then overridden by command line arguments
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    #[serde(default = "default_width")]
+    pub width: u32,
+    #[serde(default = "default_height")]
+    pub height: u32,
+    #[serde(default)]
+    pub scale_mode: ScaleMode,
+    // Horizontal field of view in degrees for a 4:3 frame. Wider frames show more at the sides
+    #[serde(default = "default_fov")]
+    pub fov: f64,
+    // Turn with the mouse (captured in relative mode while the window has focus)
+    #[serde(default)]
+    pub mouse_look: bool,
+    #[serde(default = "default_mouse_sensitivity")]
+    pub mouse_sensitivity: f64,
+    #[serde(default)]
+    pub invert_mouse: bool,
+    // Fraction of gamepad stick travel ignored around the centre
+    #[serde(default = "default_pad_dead_zone")]
+    pub pad_dead_zone: f64,
+    // Exponent applied to stick travel past the dead zone. 1.0 is linear, higher gives finer control near the centre
+    #[serde(default = "default_pad_curve")]
+    pub pad_curve: f64,
+}
+
+fn default_width() -> u32 { DEFAULT_WIDTH }
+fn default_height() -> u32 { DEFAULT_HEIGHT }
+fn default_fov() -> f64 { DEFAULT_FOV }
+fn default_mouse_sensitivity() -> f64 { 1.0 }
+fn default_pad_dead_zone() -> f64 { 0.2 }
+fn default_pad_curve() -> f64 { 2.0 }
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            scale_mode: ScaleMode::default(),
+            fov: DEFAULT_FOV,
+            mouse_look: false,
+            mouse_sensitivity: default_mouse_sensitivity(),
+            invert_mouse: false,
+            pad_dead_zone: default_pad_dead_zone(),
+            pad_curve: default_pad_curve(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config, Box<dyn Error>> {
+        return Config::load_from(CONFIG_PATH);
+    }
+
+    // Config file at path if there is one, defaults otherwise
+    fn load_from(path: &str) -> Result<Config, Box<dyn Error>> {
+        if !Path::new(path).exists() {
+            return Ok(Config::default());
+        }
+        let mut file = File::open(path)?;
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
+        let config: Config = serde_json::from_str(&data)?;
+        if config.width == 0 || config.height == 0 {
+            return Err(format!("{}: width and height must be at least 1", path).into());
+        }
+        if !valid_fov(config.fov) {
+            return Err(format!("{}: fov must be between {} and {} degrees", path, MIN_FOV, MAX_FOV).into());
+        }
+        if !(config.mouse_sensitivity > 0.0) {
+            return Err(format!("{}: mouse_sensitivity must be more than 0", path).into());
+        }
+        if !(config.pad_dead_zone >= 0.0 && config.pad_dead_zone < 1.0) {
+            return Err(format!("{}: pad_dead_zone must be at least 0 and less than 1", path).into());
+        }
+        if !(config.pad_curve > 0.0) {
+            return Err(format!("{}: pad_curve must be more than 0", path).into());
+        }
+
+        return Ok(config);
+    }
+
+    // Take out the options this understands (--resolution WxH, --scale-mode letterbox|stretch, --fov degrees)
+    // and leave the rest
+    pub fn apply_args(&mut self, args: &mut Vec<String>) -> Result<(), Box<dyn Error>> {
+        while let Some(i) = args.iter().position(|a| a == "--resolution" || a == "--scale-mode" || a == "--fov") {
+            if i + 1 >= args.len() {
+                return Err(format!("{} needs a value", args[i]).into());
+            }
+            let value = args.remove(i + 1);
+            let option = args.remove(i);
+            if option == "--resolution" {
+                let (width, height) = parse_resolution(&value)?;
+                self.width = width;
+                self.height = height;
+            } else if option == "--fov" {
+                self.fov = match value.parse() {
+                    Ok(fov) if valid_fov(fov) => fov,
+                    _ => return Err(format!("bad fov {}, expected degrees between {} and {}", value, MIN_FOV, MAX_FOV).into()),
+                };
+            } else {
+                self.scale_mode = match value.as_str() {
+                    "letterbox" => ScaleMode::Letterbox,
+                    "stretch" => ScaleMode::Stretch,
+                    _ => return Err(format!("unknown scale mode {}, expected letterbox or stretch", value).into()),
+                };
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Render resolution to use in a window of the given size
+    pub fn render_size(&self, window_width: u32, window_height: u32) -> (u32, u32) {
+        match self.scale_mode {
+            ScaleMode::Stretch => (window_width.max(1), window_height.max(1)),
+            ScaleMode::Letterbox => {
+                // Largest size with the configured aspect ratio that fits the window
+                let aspect = self.width as f64 / self.height as f64;
+                let width = (window_width as f64).min(window_height as f64 * aspect);
+                let height = width / aspect;
+                ((width as u32).max(1), (height as u32).max(1))
+            }
+        }
+    }
+}
+
+fn valid_fov(fov: f64) -> bool {
+    fov >= MIN_FOV && fov <= MAX_FOV
+}
+
+// "640x480" -> (640, 480)
+fn parse_resolution(s: &str) -> Result<(u32, u32), Box<dyn Error>> {
+    let bad = || format!("bad resolution {}, expected WIDTHxHEIGHT e.g. 800x600", s);
+    let mut parts = s.splitn(2, 'x');
+    let width: u32 = parts.next().ok_or_else(bad)?.parse().map_err(|_| bad())?;
+    let height: u32 = parts.next().ok_or_else(bad)?.parse().map_err(|_| bad())?;
+    if width == 0 || height == 0 {
+        return Err(bad().into());
+    }
+
+    return Ok((width, height));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn apply_args_takes_out_its_options() {
+        let mut config = Config::default();
+        let mut list = args(&["game", "--resolution", "640x480", "map", "--fov", "90", "--scale-mode", "stretch", "--headless"]);
+        config.apply_args(&mut list).unwrap();
+        assert_eq!(list, args(&["game", "map", "--headless"]));
+        assert_eq!((config.width, config.height), (640, 480));
+        assert_eq!(config.fov, 90.0);
+        assert_eq!(config.scale_mode, ScaleMode::Stretch);
+    }
+
+    #[test]
+    fn apply_args_leaves_defaults_without_options() {
+        let mut config = Config::default();
+        let mut list = args(&["game", "map"]);
+        config.apply_args(&mut list).unwrap();
+        assert_eq!(list, args(&["game", "map"]));
+        assert_eq!((config.width, config.height), (DEFAULT_WIDTH, DEFAULT_HEIGHT));
+        assert_eq!(config.fov, DEFAULT_FOV);
+        assert_eq!(config.scale_mode, ScaleMode::Letterbox);
+    }
+
+    #[test]
+    fn apply_args_rejects_bad_values() {
+        assert!(Config::default().apply_args(&mut args(&["game", "--fov"])).is_err());
+        assert!(Config::default().apply_args(&mut args(&["game", "--scale-mode", "zoom"])).is_err());
+        assert!(Config::default().apply_args(&mut args(&["game", "--resolution", "big"])).is_err());
+    }
+
+    #[test]
+    fn fov_range() {
+        for fov in ["10", "66.5", "170"].iter() {
+            let mut config = Config::default();
+            config.apply_args(&mut args(&["--fov", fov])).unwrap();
+            assert_eq!(config.fov, fov.parse::<f64>().unwrap());
+        }
+        for fov in ["9.9", "170.1", "0", "-66", "wide", "NaN"].iter() {
+            assert!(Config::default().apply_args(&mut args(&["--fov", fov])).is_err(), "{} accepted", fov);
+        }
+    }
+
+    #[test]
+    fn parse_resolutions() {
+        assert_eq!(parse_resolution("800x600").unwrap(), (800, 600));
+        assert_eq!(parse_resolution("1x1").unwrap(), (1, 1));
+        for s in ["", "800", "800x", "x600", "0x600", "800x0", "800x600x2", "-800x600", "800 x 600"].iter() {
+            assert!(parse_resolution(s).is_err(), "{} accepted", s);
+        }
+    }
+
+    #[test]
+    fn missing_file_gives_defaults() {
+        let config = Config::load_from("./data/no_such_config.json").unwrap();
+        assert_eq!((config.width, config.height), (DEFAULT_WIDTH, DEFAULT_HEIGHT));
+        assert_eq!(config.fov, DEFAULT_FOV);
+    }
+
+    #[test]
+    fn partial_file_gets_defaults_for_the_rest() {
+        let path = std::env::temp_dir().join("rustenstein_partial_config.json");
+        std::fs::write(&path, r#"{ "width": 640, "mouse_look": true }"#).unwrap();
+        let config = Config::load_from(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!((config.width, config.height), (640, DEFAULT_HEIGHT));
+        assert!(config.mouse_look);
+        assert_eq!(config.fov, DEFAULT_FOV);
+        assert_eq!(config.pad_curve, default_pad_curve());
+    }
+
+    #[test]
+    fn shipped_config_matches_defaults() {
+        let config = Config::load().unwrap();
+        assert_eq!((config.width, config.height), (DEFAULT_WIDTH, DEFAULT_HEIGHT));
+        assert_eq!(config.fov, DEFAULT_FOV);
+    }
+}
@@ -0,0 +1,120 @@
+// Fraction of incoming damage that armour soaks up while it lasts
+const ARMOUR_ABSORB: f64 = 0.5;
+
+// Hit points of the player or an entity
+#[derive(Debug, Clone, Copy)]
+pub struct Health {
+    pub health: f64,
+    pub max_health: f64,
+    pub armour: f64, // Absorbs part of each hit until it's used up
+    pub resistance: f64, // Fraction of all damage ignored, 0.0 -> 1.0
+}
+
+impl Health {
+    pub fn new(health: f64, armour: f64, resistance: f64) -> Health {
+        Health {
+            health: health,
+            max_health: health,
+            armour: armour,
+            resistance: resistance.max(0.0).min(1.0),
+        }
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.health <= 0.0
+    }
+
+    // Apply a hit: resistance first, then armour takes its share. Returns the health actually lost
+    pub fn apply(&mut self, amount: f64) -> f64 {
+        let amount = amount * (1.0 - self.resistance);
+        let absorbed = (amount * ARMOUR_ABSORB).min(self.armour);
+        self.armour -= absorbed;
+        let lost = (amount - absorbed).min(self.health);
+        self.health -= lost;
+        return lost;
+    }
+
+    pub fn restore(&mut self) {
+        self.health = self.max_health;
+    }
+}
+
+// Who got hurt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DamageTarget {
+    Player,
+    Entity(u32), // Entity id
+}
+
+// Emitted every time something takes damage, for the HUD (and anything else that wants to react)
+#[derive(Debug, Clone, Copy)]
+pub struct DamageEvent {
+    pub target: DamageTarget,
+    pub amount: f64, // Health lost
+    pub killed: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_armour_takes_it_all() {
+        let mut h = Health::new(100.0, 0.0, 0.0);
+        assert_eq!(h.apply(30.0), 30.0);
+        assert_eq!(h.health, 70.0);
+    }
+
+    #[test]
+    fn armour_takes_its_share() {
+        let mut h = Health::new(100.0, 50.0, 0.0);
+        assert_eq!(h.apply(40.0), 40.0 * (1.0 - ARMOUR_ABSORB));
+        assert_eq!(h.armour, 50.0 - 40.0 * ARMOUR_ABSORB);
+    }
+
+    #[test]
+    fn used_up_armour_passes_the_rest_on() {
+        let mut h = Health::new(100.0, 5.0, 0.0);
+        assert_eq!(h.apply(40.0), 35.0);
+        assert_eq!(h.armour, 0.0);
+        assert_eq!(h.apply(10.0), 10.0);
+    }
+
+    #[test]
+    fn resistance_comes_before_armour() {
+        let mut h = Health::new(100.0, 50.0, 0.5);
+        // 40 resisted down to 20, armour takes half of that
+        assert_eq!(h.apply(40.0), 10.0);
+        assert_eq!(h.armour, 40.0);
+        assert_eq!(h.health, 90.0);
+    }
+
+    #[test]
+    fn resistance_is_clamped() {
+        assert_eq!(Health::new(100.0, 0.0, 2.0).resistance, 1.0);
+        assert_eq!(Health::new(100.0, 0.0, -1.0).resistance, 0.0);
+        let mut h = Health::new(100.0, 0.0, 2.0);
+        assert_eq!(h.apply(50.0), 0.0);
+        assert_eq!(h.health, 100.0);
+    }
+
+    #[test]
+    fn dies_at_zero() {
+        let mut h = Health::new(50.0, 0.0, 0.0);
+        assert_eq!(h.apply(49.0), 49.0);
+        assert!(!h.is_dead());
+        // Only what was left counts as lost
+        assert_eq!(h.apply(10.0), 1.0);
+        assert_eq!(h.health, 0.0);
+        assert!(h.is_dead());
+        assert_eq!(h.apply(10.0), 0.0);
+    }
+
+    #[test]
+    fn restore_refills_health() {
+        let mut h = Health::new(80.0, 0.0, 0.0);
+        h.apply(60.0);
+        h.restore();
+        assert_eq!(h.health, 80.0);
+    }
+}
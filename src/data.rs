@@ -2,18 +2,155 @@ extern crate image;
 extern crate glob;
 
 use glob::glob;
+use cgmath::Vector2;
+use cgmath::InnerSpace;
 use serde::{Serialize, Deserialize};
 
 use std::fs::File;
 use std::io::Read;
 use std::error::Error;
 
+use crate::pickup::KeyColour;
+use crate::validation::MapAssets;
+use crate::validation::MapErrors;
+use crate::validation::validate_map;
+
+const DOOR_SPEED: f64 = 1.0; // Fraction of the door opened/closed per second
+const DOOR_OPEN_TIME: f64 = 3.0; // Seconds a door stays open before closing by itself
+const PUSHWALL_SPEED: f64 = 1.0; // Cells per second
+const PUSHWALL_DISTANCE: u32 = 2; // Max cells a pushwall slides
+const COLLISION_ITERATIONS: u32 = 4; // Push out passes per collision check
+
+// Which kind of grid line a ray crossed. X: a vertical line (x = n), Y: a horizontal line (y = n)
+#[derive(Serialize, PartialEq, Clone, Copy, Debug)]
+pub enum WallSide {
+    X,
+    Y,
+}
+
+// Ray hit on something inside a cell (door slab, sliding pushwall) rather than on the cell's edge
+#[derive(Clone, Copy, Debug)]
+pub struct InnerHit {
+    pub dist: f64, // Along the ray, same units as RayHit::dist
+    pub tex_x: f64, // 0.0 -> 1.0 across the texture
+    pub tex: i32,
+    pub side: WallSide,
+}
+
+// Where a ray stopped. dist is along the ray in multiples of the ray direction's length
+// (perpendicular distance to the camera plane for rays cast through it)
+#[derive(Clone, Copy, Debug)]
+pub struct RayHit {
+    pub cell: Vector2<i32>,
+    pub side: WallSide,
+    pub point: Vector2<f64>, // Exact world position of the hit
+    pub dist: f64,
+    pub inner: Option<InnerHit>, // Set if it hit a door or pushwall inside the cell
+}
+
 // Note all textures are 1-indexed since 0 is special
 #[derive(Serialize, Debug)]
 pub struct MapCell {
     pub wall_tex: i32,
     pub floor_tex: i32,
     pub ceil_tex: i32,
+    pub door: Option<usize>, // Index into WorldMap::doors if this cell is a door
+}
+
+#[derive(Serialize, PartialEq, Clone, Copy, Debug)]
+pub enum DoorState {
+    Closed,
+    Opening,
+    Open,
+    Closing,
+}
+
+// Sliding door, drawn recessed half a cell into its tile
+#[derive(Serialize, Debug)]
+pub struct Door {
+    pub x: u32,
+    pub y: u32,
+    pub tex: i32,
+    pub side: WallSide, // Orientation of the door slab. X: slab at x + 0.5, Y: slab at y + 0.5
+    pub open_amount: f64, // 0.0 fully closed -> 1.0 fully open
+    pub state: DoorState,
+    pub lock: Option<KeyColour>, // Only opens for a player holding this key
+    timer: f64, // Time left before an open door starts closing
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DoorJSON {
+    pub x: u32,
+    pub y: u32,
+    pub tex: i32,
+    #[serde(default)]
+    pub lock: Option<KeyColour>,
+}
+
+#[derive(Serialize, PartialEq, Clone, Copy, Debug)]
+pub enum PushWallState {
+    Hidden, // Looks like any other wall, waiting to be found
+    Moving,
+    Done, // Solid again at its new location
+}
+
+// Secret wall that slides away when the player uses it
+#[derive(Serialize, Debug)]
+pub struct PushWall {
+    pub x: u32, // Original cell
+    pub y: u32,
+    pub tex: i32,
+    pub step: (i32, i32), // Direction it's sliding in, one cell
+    pub distance: u32, // How many cells it will slide
+    pub offset: f64, // How far it has slid so far, in cells
+    pub state: PushWallState,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PushWallJSON {
+    pub x: u32,
+    pub y: u32,
+}
+
+// What's drawn beyond the edge of the map
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OutsideMode {
+    Void, // Flat colour
+    Border, // The nearest cell on the map edge repeats forever
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Outside {
+    pub mode: OutsideMode,
+    #[serde(default = "default_void_color")]
+    pub color: [u8; 3],
+}
+
+impl Default for Outside {
+    fn default() -> Self {
+        Outside {
+            mode: OutsideMode::Void,
+            color: default_void_color(),
+        }
+    }
+}
+
+fn default_void_color() -> [u8; 3] {
+    [128, 128, 128]
+}
+
+fn default_view_distance() -> f64 {
+    64.0
+}
+
+// Where the player can start the level, and which way they're facing
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlayerStartJSON {
+    pub x: f64,
+    pub y: f64,
+    pub dir_x: f64,
+    pub dir_y: f64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -27,17 +164,30 @@ pub struct EntityJSON {
     pub dir_y: f64,
     #[serde(default)]
     pub animation: String,
+    #[serde(default)]
+    pub tags: Vec<String>, // Added to the template's tags
 }
 
 // JSON definition of map. Gets transformed into WorldMap by combining the 3 grids into 1 cell vector
 #[derive(Serialize, Deserialize, Debug)]
-struct WorldMapJSON {
+pub struct WorldMapJSON {
     pub height: u32,
     pub width: u32,
-    wall_grid: Vec<Vec<i32>>,
-    floor_grid: Vec<Vec<i32>>,
-    ceil_grid: Vec<Vec<i32>>,
+    pub wall_grid: Vec<Vec<i32>>,
+    pub floor_grid: Vec<Vec<i32>>,
+    pub ceil_grid: Vec<Vec<i32>>,
+    #[serde(default)]
+    pub outside: Outside,
+    #[serde(default = "default_view_distance")]
+    pub view_distance: f64, // Rays stop and nothing is drawn past this many cells
+    #[serde(default)]
+    pub player_starts: Vec<PlayerStartJSON>,
+    #[serde(default)]
     pub entities: Vec<EntityJSON>,
+    #[serde(default)]
+    pub doors: Vec<DoorJSON>,
+    #[serde(default)]
+    pub pushwalls: Vec<PushWallJSON>,
 }
 
 #[derive(Serialize, Debug)]
@@ -45,22 +195,50 @@ pub struct WorldMap  {
     pub height: u32,
     pub width: u32,
     grid: Vec<MapCell>,
+    pub outside: Outside,
+    pub view_distance: f64,
+    pub player_starts: Vec<PlayerStartJSON>,
     pub entities: Vec<EntityJSON>,
+    pub doors: Vec<Door>,
+    pub pushwalls: Vec<PushWall>,
+    pub secrets_total: u32,
+    pub secrets_found: u32,
 }
 
 impl WorldMap {
-    pub fn load_map(mapname: &str) -> Result<WorldMap, Box<dyn Error>> {
+    // Load and validate a map against the loaded assets. Fails with every problem found in the map file
+    pub fn load_map(mapname: &str, assets: &MapAssets) -> Result<WorldMap, Box<dyn Error>> {
         let path = format!("./data/maps/{}/{}.json", mapname, mapname);
         let mut file = File::open(path)?;
         let mut data = String::new();
         file.read_to_string(&mut data)?;
 
         let map_json: WorldMapJSON = serde_json::from_str(&data)?;
+        let problems = validate_map(&map_json, assets);
+        if !problems.is_empty() {
+            return Err(Box::new(MapErrors {
+                map: String::from(mapname),
+                problems: problems,
+            }));
+        }
+
+        return Ok(WorldMap::from_json(map_json));
+    }
+
+    // Build the map from a file that's already passed validate_map
+    pub fn from_json(map_json: WorldMapJSON) -> WorldMap {
         let mut map = WorldMap{
             height: map_json.height,
             width: map_json.width,
             grid: vec![],
+            outside: map_json.outside,
+            view_distance: map_json.view_distance,
+            player_starts: map_json.player_starts,
             entities: map_json.entities,
+            doors: vec![],
+            pushwalls: vec![],
+            secrets_total: map_json.pushwalls.len() as u32,
+            secrets_found: 0,
         };
         for i in 0..map_json.height as usize {
             for j in (0..map_json.width as usize).rev() {
@@ -69,15 +247,838 @@ impl WorldMap {
                         wall_tex: map_json.wall_grid[i][j],
                         floor_tex: map_json.floor_grid[i][j],
                         ceil_tex: map_json.ceil_grid[i][j],
+                        door: None,
                     },
                 );
             }
         }
+        for d in map_json.doors {
+            // Doors sit between two walls. Walls to the left and right mean the slab runs along x
+            let side = match map.is_wall(d.x as i32 - 1, d.y as i32) && map.is_wall(d.x as i32 + 1, d.y as i32) {
+                true => WallSide::Y,
+                false => WallSide::X,
+            };
+            let index = map.doors.len();
+            map.grid[(d.y * map.width + d.x) as usize].door = Some(index);
+            map.doors.push(Door {
+                x: d.x,
+                y: d.y,
+                tex: d.tex,
+                side: side,
+                open_amount: 0.0,
+                state: DoorState::Closed,
+                lock: d.lock,
+                timer: 0.0,
+            });
+        }
+        for p in map_json.pushwalls {
+            map.pushwalls.push(PushWall {
+                x: p.x,
+                y: p.y,
+                tex: map.get_cell(p.x, p.y).wall_tex,
+                step: (0, 0),
+                distance: 0,
+                offset: 0.0,
+                state: PushWallState::Hidden,
+            });
+        }
 
-        return Ok(map);
+        return map;
     }
 
     pub fn get_cell(&self, x: u32, y: u32) -> &MapCell {
         return &self.grid[(y * self.width + x) as usize];
     }
+
+    pub fn get_cell_mut(&mut self, x: u32, y: u32) -> &mut MapCell {
+        return &mut self.grid[(y * self.width + x) as usize];
+    }
+
+    // Cell whose floor and ceiling show at (x, y), which may be off the map.
+    // None if nothing is there (void)
+    pub fn surface_cell(&self, x: i32, y: i32) -> Option<&MapCell> {
+        if self.in_bounds(x, y) {
+            return Some(self.get_cell(x as u32, y as u32));
+        }
+        match self.outside.mode {
+            OutsideMode::Void => None,
+            OutsideMode::Border => Some(self.get_cell(
+                x.max(0).min(self.width as i32 - 1) as u32,
+                y.max(0).min(self.height as i32 - 1) as u32,
+            )),
+        }
+    }
+
+    pub fn in_bounds(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && (x as u32) < self.width && (y as u32) < self.height
+    }
+
+    fn is_wall(&self, x: i32, y: i32) -> bool {
+        self.in_bounds(x, y) && self.get_cell(x as u32, y as u32).wall_tex > 0
+    }
+
+    // Whether players/entities are blocked by this cell. Anything outside the map is solid
+    pub fn is_solid(&self, x: i32, y: i32) -> bool {
+        if !self.in_bounds(x, y) {
+            return true;
+        }
+        let cell = self.get_cell(x as u32, y as u32);
+        let solid = match cell.door {
+            Some(d) => self.doors[d].state != DoorState::Open,
+            None => cell.wall_tex > 0,
+        };
+        return solid || self.moving_pushwall_at(x, y).is_some();
+    }
+
+    // Move a circle (player, entity) by delta, sliding along any solid cells it runs into.
+    // Returns the new centre. The circle never ends up overlapping a solid cell, corners included
+    pub fn move_circle(&self, pos: Vector2<f64>, delta: Vector2<f64>, radius: f64) -> Vector2<f64> {
+        // Small enough steps that the circle can't jump over a cell edge
+        let steps = (delta.magnitude() / (radius * 0.5)).ceil().max(1.0);
+        let step = delta / steps;
+        let mut pos = pos;
+        for _ in 0..steps as u32 {
+            pos = self.push_out_circle(pos + step, radius);
+        }
+        return pos;
+    }
+
+    // Push a circle out of every solid cell it overlaps
+    pub fn push_out_circle(&self, pos: Vector2<f64>, radius: f64) -> Vector2<f64> {
+        let mut pos = pos;
+        // Being pushed out of one cell can push into another (inside corners), so go round a few times
+        for _ in 0..COLLISION_ITERATIONS {
+            let mut solid = vec![];
+            for y in (pos.y - radius).floor() as i32..=(pos.y + radius).floor() as i32 {
+                for x in (pos.x - radius).floor() as i32..=(pos.x + radius).floor() as i32 {
+                    if self.is_solid(x, y) {
+                        solid.push((x, y));
+                    }
+                }
+            }
+            // Nearest cells first. Otherwise sliding along a flat wall gets knocked sideways by the corner
+            // where two of its cells meet, which stops overlapping once the nearer cell has pushed the circle out
+            solid.sort_by(|a, b| {
+                let dist_a = (pos - closest_point(pos, a.0, a.1)).magnitude2();
+                let dist_b = (pos - closest_point(pos, b.0, b.1)).magnitude2();
+                dist_a.partial_cmp(&dist_b).unwrap()
+            });
+            let mut moved = false;
+            for (x, y) in solid {
+                let diff = pos - closest_point(pos, x, y);
+                let dist = diff.magnitude();
+                if dist >= radius {
+                    continue;
+                }
+                if dist > 0.0 {
+                    pos += diff / dist * (radius - dist);
+                } else {
+                    // Centre is inside the cell, leave by the nearest edge
+                    let left = pos.x - x as f64;
+                    let right = x as f64 + 1.0 - pos.x;
+                    let top = pos.y - y as f64;
+                    let bottom = y as f64 + 1.0 - pos.y;
+                    let nearest = left.min(right).min(top).min(bottom);
+                    if nearest == left {
+                        pos.x = x as f64 - radius;
+                    } else if nearest == right {
+                        pos.x = x as f64 + 1.0 + radius;
+                    } else if nearest == top {
+                        pos.y = y as f64 - radius;
+                    } else {
+                        pos.y = y as f64 + 1.0 + radius;
+                    }
+                }
+                moved = true;
+            }
+            if !moved {
+                break;
+            }
+        }
+        return pos;
+    }
+
+    // Step a ray from origin through the grid (DDA) until it hits a wall, door or pushwall.
+    // None if it leaves the map or goes further than max_dist (in multiples of ray_dir) first
+    pub fn cast_ray(&self, origin: Vector2<f64>, ray_dir: Vector2<f64>, max_dist: f64) -> Option<RayHit> {
+        // Which box we're in
+        let mut curr_grid = Vector2::new(origin.x.floor() as i32, origin.y.floor() as i32);
+        // Length of ray from any x/y side to next x/y side
+        let delta_dist = Vector2::new((1.0 / ray_dir.x).abs(), (1.0 / ray_dir.y).abs());
+        let step_x: i32;
+        let step_y: i32;
+        let mut side_dist_x: f64;
+        let mut side_dist_y: f64;
+        if ray_dir.x < 0.0 {
+            step_x = -1;
+            side_dist_x = (origin.x - curr_grid.x as f64) * delta_dist.x;
+        } else {
+            step_x = 1;
+            side_dist_x = (curr_grid.x as f64 + 1.0 - origin.x) * delta_dist.x;
+        }
+        if ray_dir.y < 0.0 {
+            step_y = -1;
+            side_dist_y = (origin.y - curr_grid.y as f64) * delta_dist.y;
+        } else {
+            step_y = 1;
+            side_dist_y = (curr_grid.y as f64 + 1.0 - origin.y) * delta_dist.y;
+        }
+
+        // start DDA
+        let mut side: WallSide;
+        let mut inner_hit: Option<InnerHit> = None;
+        let hit = loop {
+            // Distance travelled to get into the next cell
+            let travelled: f64;
+            if side_dist_x < side_dist_y {
+                travelled = side_dist_x;
+                side_dist_x += delta_dist.x;
+                curr_grid.x += step_x;
+                side = WallSide::X;
+            } else {
+                travelled = side_dist_y;
+                side_dist_y += delta_dist.y;
+                curr_grid.y += step_y;
+                side = WallSide::Y;
+            }
+            // Ray left the map or went past max_dist. Nothing to hit
+            if travelled > max_dist || !self.in_bounds(curr_grid.x, curr_grid.y) {
+                break false;
+            }
+            let cell = self.get_cell(curr_grid.x as u32, curr_grid.y as u32);
+            if cell.wall_tex > 0 {
+                break true;
+            }
+            if let Some(d) = cell.door {
+                inner_hit = self.doors[d].intersect(origin, ray_dir);
+            }
+            if let Some(p) = self.moving_pushwall_at(curr_grid.x, curr_grid.y) {
+                inner_hit = p.intersect(origin, ray_dir);
+            }
+            if let Some(hit) = inner_hit {
+                side = hit.side;
+                break true;
+            }
+        };
+        if !hit {
+            return None;
+        }
+        let dist = match (inner_hit, side) {
+            (Some(hit), _) => hit.dist,
+            (None, WallSide::X) => {
+                (curr_grid.x as f64 - origin.x + (1.0 - step_x as f64) / 2.0) / ray_dir.x
+            }
+            (None, WallSide::Y) => {
+                (curr_grid.y as f64 - origin.y + (1.0 - step_y as f64) / 2.0) / ray_dir.y
+            }
+        };
+        // Doors and pushwalls are inside the cell, so they can be past max_dist even though the cell isn't
+        if dist > max_dist {
+            return None;
+        }
+        return Some(RayHit {
+            cell: curr_grid,
+            side: side,
+            point: origin + ray_dir * dist,
+            dist: dist,
+            inner: inner_hit,
+        });
+    }
+
+    // First thing blocking the straight line between two points, or None if there's a clear line of sight
+    pub fn trace(&self, from: Vector2<f64>, to: Vector2<f64>) -> Option<RayHit> {
+        let ray_dir = to - from;
+        if ray_dir.magnitude2() == 0.0 {
+            return None;
+        }
+        return self.cast_ray(from, ray_dir, 1.0);
+    }
+
+    pub fn line_of_sight(&self, from: Vector2<f64>, to: Vector2<f64>) -> bool {
+        self.trace(from, to).is_none()
+    }
+
+    // Sliding pushwall currently overlapping this cell, if any
+    pub fn moving_pushwall_at(&self, x: i32, y: i32) -> Option<&PushWall> {
+        self.pushwalls.iter().find(|p| p.state == PushWallState::Moving && p.covers(x, y))
+    }
+
+    // Try to push a hidden wall at (x, y) one step at a time in direction step.
+    // occupants: (centre, radius) of the player and anything collidable. The wall won't slide into a cell they overlap.
+    // Returns false if there's no pushwall there or nothing behind it to slide into
+    pub fn push_wall(&mut self, x: i32, y: i32, step: (i32, i32), occupants: &[(Vector2<f64>, f64)]) -> bool {
+        let index = match self.pushwalls.iter().position(|p| p.state == PushWallState::Hidden && p.x as i32 == x && p.y as i32 == y) {
+            Some(i) => i,
+            None => return false,
+        };
+        // Slide until something is in the way, up to PUSHWALL_DISTANCE cells
+        let mut distance = 0;
+        while distance < PUSHWALL_DISTANCE {
+            let next = (x + step.0 * (distance as i32 + 1), y + step.1 * (distance as i32 + 1));
+            if self.is_solid(next.0, next.1) || self.get_cell(next.0 as u32, next.1 as u32).door.is_some() {
+                break;
+            }
+            if occupants.iter().any(|(pos, radius)| (pos - closest_point(*pos, next.0, next.1)).magnitude() < *radius) {
+                break;
+            }
+            distance += 1;
+        }
+        if distance == 0 {
+            return false;
+        }
+        // Wall leaves the grid while it's moving, and gets drawn/collided as a moving block instead
+        self.get_cell_mut(x as u32, y as u32).wall_tex = 0;
+        let pushwall = &mut self.pushwalls[index];
+        pushwall.step = step;
+        pushwall.distance = distance;
+        pushwall.state = PushWallState::Moving;
+        self.secrets_found += 1;
+
+        return true;
+    }
+
+    // Slide moving pushwalls. Once they arrive they're put back into the grid as a solid wall
+    pub fn tick_pushwalls(&mut self, frame_time: f64) {
+        let mut arrived = vec![];
+        for p in self.pushwalls.iter_mut().filter(|p| p.state == PushWallState::Moving) {
+            p.offset += PUSHWALL_SPEED * frame_time;
+            if p.offset >= p.distance as f64 {
+                p.offset = p.distance as f64;
+                p.state = PushWallState::Done;
+                let end = p.cell_at(p.distance);
+                arrived.push((end.0 as u32, end.1 as u32, p.tex));
+            }
+        }
+        for (x, y, tex) in arrived {
+            self.get_cell_mut(x, y).wall_tex = tex;
+        }
+    }
+
+    pub fn get_door_mut(&mut self, x: i32, y: i32) -> Option<&mut Door> {
+        if !self.in_bounds(x, y) {
+            return None;
+        }
+        let index = self.get_cell(x as u32, y as u32).door?;
+        return Some(&mut self.doors[index]);
+    }
+}
+
+impl PushWall {
+    // Cell `cells` steps away from the start along the sliding direction
+    pub fn cell_at(&self, cells: u32) -> (i32, i32) {
+        (self.x as i32 + self.step.0 * cells as i32, self.y as i32 + self.step.1 * cells as i32)
+    }
+
+    // Intersect a ray with the sliding block
+    pub fn intersect(&self, origin: Vector2<f64>, ray_dir: Vector2<f64>) -> Option<InnerHit> {
+        let (block_x, block_y) = self.position();
+        // Distances along the ray where it's between the block's x sides, and its y sides
+        let slab = |pos: f64, dir: f64, min: f64| -> (f64, f64) {
+            if dir == 0.0 {
+                return match pos >= min && pos < min + 1.0 {
+                    true => (std::f64::NEG_INFINITY, std::f64::INFINITY),
+                    false => (std::f64::INFINITY, std::f64::NEG_INFINITY),
+                };
+            }
+            let t1 = (min - pos) / dir;
+            let t2 = (min + 1.0 - pos) / dir;
+            (t1.min(t2), t1.max(t2))
+        };
+        let (x_enter, x_exit) = slab(origin.x, ray_dir.x, block_x);
+        let (y_enter, y_exit) = slab(origin.y, ray_dir.y, block_y);
+        let dist = x_enter.max(y_enter);
+        if dist > x_exit.min(y_exit) || dist <= 0.0 {
+            return None;
+        }
+        // Side is whichever pair of faces the ray entered through last
+        let (side, mut tex_x) = match x_enter > y_enter {
+            true => (WallSide::X, origin.y + dist * ray_dir.y - block_y),
+            false => (WallSide::Y, origin.x + dist * ray_dir.x - block_x),
+        };
+        // Same flipping as plain walls so the texture doesn't mirror when the block starts moving
+        if (side == WallSide::X && ray_dir.x > 0.0) || (side == WallSide::Y && ray_dir.y < 0.0) {
+            tex_x = 1.0 - tex_x;
+        }
+        return Some(InnerHit {
+            dist: dist,
+            tex_x: tex_x.max(0.0),
+            tex: self.tex,
+            side: side,
+        });
+    }
+
+    // Top left corner of the block as it slides
+    pub fn position(&self) -> (f64, f64) {
+        (self.x as f64 + self.step.0 as f64 * self.offset, self.y as f64 + self.step.1 as f64 * self.offset)
+    }
+
+    // Whether the moving block overlaps a cell. Mid slide it straddles two cells
+    pub fn covers(&self, x: i32, y: i32) -> bool {
+        let back = self.cell_at(self.offset.floor() as u32);
+        let front = self.cell_at(self.offset.ceil() as u32);
+        (x, y) == back || (x, y) == front
+    }
+}
+
+impl Door {
+    // Intersect a ray with the door's slab, which is recessed to the middle of its cell
+    // Texture coord is relative to the door panel's sliding edge.
+    // None if the ray leaves the cell first or passes through the opened gap
+    pub fn intersect(&self, origin: Vector2<f64>, ray_dir: Vector2<f64>) -> Option<InnerHit> {
+        let (dist, cross) = match self.side {
+            WallSide::X => {
+                let dist = (self.x as f64 + 0.5 - origin.x) / ray_dir.x;
+                (dist, origin.y + dist * ray_dir.y - self.y as f64)
+            }
+            WallSide::Y => {
+                let dist = (self.y as f64 + 0.5 - origin.y) / ray_dir.y;
+                (dist, origin.x + dist * ray_dir.x - self.x as f64)
+            }
+        };
+        // Ray runs parallel to the slab, or it's behind the origin
+        if !dist.is_finite() || dist <= 0.0 {
+            return None;
+        }
+        // Door panel slides towards +cross as it opens, leaving a gap of open_amount behind it
+        if cross < self.open_amount || cross >= 1.0 {
+            return None;
+        }
+        return Some(InnerHit {
+            dist: dist,
+            tex_x: cross - self.open_amount,
+            tex: self.tex,
+            side: self.side,
+        });
+    }
+
+    // Player pressed use on the door
+    pub fn activate(&mut self) {
+        match self.state {
+            DoorState::Closed | DoorState::Closing => self.state = DoorState::Opening,
+            DoorState::Open => self.state = DoorState::Closing,
+            DoorState::Opening => {},
+        }
+    }
+
+    // Slide the door. occupied: something is standing in the doorway, so it can't close
+    pub fn tick(&mut self, frame_time: f64, occupied: bool) {
+        match self.state {
+            DoorState::Closed => {},
+            DoorState::Opening => {
+                self.open_amount += DOOR_SPEED * frame_time;
+                if self.open_amount >= 1.0 {
+                    self.open_amount = 1.0;
+                    self.state = DoorState::Open;
+                    self.timer = DOOR_OPEN_TIME;
+                }
+            },
+            DoorState::Open => {
+                if !occupied {
+                    self.timer -= frame_time;
+                    if self.timer <= 0.0 {
+                        self.state = DoorState::Closing;
+                    }
+                }
+            },
+            DoorState::Closing => {
+                if occupied {
+                    self.state = DoorState::Opening;
+                    return;
+                }
+                self.open_amount -= DOOR_SPEED * frame_time;
+                if self.open_amount <= 0.0 {
+                    self.open_amount = 0.0;
+                    self.state = DoorState::Closed;
+                }
+            },
+        }
+    }
+}
+
+// Closest point of cell (x, y) to pos
+fn closest_point(pos: Vector2<f64>, x: i32, y: i32) -> Vector2<f64> {
+    Vector2::new(
+        pos.x.max(x as f64).min(x as f64 + 1.0),
+        pos.y.max(y as f64).min(y as f64 + 1.0),
+    )
+}
+
+// Small maps for tests. '#' is a wall, 'D' a closed door, 'P' a hidden pushwall, anything else is open floor.
+// Rows are y and characters are x in world order (the JSON grid runs the other way)
+#[cfg(test)]
+impl WorldMap {
+    pub fn from_rows(rows: &[&str]) -> WorldMap {
+        let height = rows.len();
+        let width = rows[0].len();
+        let mut wall_grid = vec![];
+        let mut doors = vec![];
+        let mut pushwalls = vec![];
+        for (y, row) in rows.iter().enumerate() {
+            let mut cells: Vec<i32> = row.chars().map(|c| if c == '#' || c == 'P' { 1 } else { 0 }).collect();
+            cells.reverse();
+            wall_grid.push(cells);
+            for (x, c) in row.chars().enumerate() {
+                if c == 'D' {
+                    doors.push(DoorJSON { x: x as u32, y: y as u32, tex: 1, lock: None });
+                }
+                if c == 'P' {
+                    pushwalls.push(PushWallJSON { x: x as u32, y: y as u32 });
+                }
+            }
+        }
+        return WorldMap::from_json(WorldMapJSON {
+            height: height as u32,
+            width: width as u32,
+            wall_grid: wall_grid,
+            floor_grid: vec![vec![0; width]; height],
+            ceil_grid: vec![vec![0; width]; height],
+            outside: Outside::default(),
+            view_distance: default_view_distance(),
+            player_starts: vec![],
+            entities: vec![],
+            doors: doors,
+            pushwalls: pushwalls,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADIUS: f64 = 0.25;
+
+    fn room() -> WorldMap {
+        WorldMap::from_rows(&[
+            "######",
+            "#....#",
+            "#....#",
+            "#....#",
+            "######",
+        ])
+    }
+
+    // Vertical corridor with a door across it, slab at y = 1.5
+    fn door_corridor() -> WorldMap {
+        WorldMap::from_rows(&[
+            "#.#",
+            "#D#",
+            "#.#",
+            "###",
+        ])
+    }
+
+    fn assert_near(actual: Vector2<f64>, expected: (f64, f64)) {
+        assert!(
+            (actual.x - expected.0).abs() < 1e-9 && (actual.y - expected.1).abs() < 1e-9,
+            "expected {:?}, got {:?}", expected, actual,
+        );
+    }
+
+    #[test]
+    fn move_circle_in_open_space() {
+        let pos = room().move_circle(Vector2::new(2.5, 2.5), Vector2::new(0.5, 0.25), RADIUS);
+        assert_near(pos, (3.0, 2.75));
+    }
+
+    #[test]
+    fn move_circle_stops_at_wall() {
+        let pos = room().move_circle(Vector2::new(3.5, 2.5), Vector2::new(2.0, 0.0), RADIUS);
+        assert_near(pos, (5.0 - RADIUS, 2.5));
+    }
+
+    #[test]
+    fn move_circle_slides_along_wall() {
+        let pos = room().move_circle(Vector2::new(4.5, 2.0), Vector2::new(1.0, 0.5), RADIUS);
+        assert_near(pos, (5.0 - RADIUS, 2.5));
+    }
+
+    #[test]
+    fn move_circle_into_corner() {
+        let pos = room().move_circle(Vector2::new(1.5, 1.5), Vector2::new(-2.0, -2.0), RADIUS);
+        assert_near(pos, (1.0 + RADIUS, 1.0 + RADIUS));
+    }
+
+    #[test]
+    fn move_circle_slides_round_outside_corner() {
+        let map = WorldMap::from_rows(&[
+            "######",
+            "#....#",
+            "#.#..#",
+            "#....#",
+            "######",
+        ]);
+        // Clips the top of the pillar, gets pushed up over it and carries on past
+        let pos = map.move_circle(Vector2::new(1.5, 1.8), Vector2::new(2.0, 0.0), RADIUS);
+        assert!(pos.x > 3.4, "stuck on the corner at {:?}", pos);
+        assert!((pos.y - (2.0 - RADIUS)).abs() < 1e-9, "expected to slide along y = 1.75, got {:?}", pos);
+    }
+
+    #[test]
+    fn move_circle_cant_tunnel_through_thin_wall() {
+        let map = WorldMap::from_rows(&[
+            "#####",
+            "#.#.#",
+            "#####",
+        ]);
+        let pos = map.move_circle(Vector2::new(1.5, 1.5), Vector2::new(2.0, 0.0), RADIUS);
+        assert_near(pos, (2.0 - RADIUS, 1.5));
+    }
+
+    #[test]
+    fn move_circle_through_doors() {
+        let mut map = door_corridor();
+        let start = Vector2::new(1.5, 0.5);
+        let delta = Vector2::new(0.0, 2.0);
+        // The whole door cell blocks, not just the slab in the middle of it
+        assert_near(map.move_circle(start, delta, RADIUS), (1.5, 1.0 - RADIUS));
+        // Still solid until it's all the way open
+        map.doors[0].state = DoorState::Opening;
+        assert_near(map.move_circle(start, delta, RADIUS), (1.5, 1.0 - RADIUS));
+        map.doors[0].state = DoorState::Open;
+        assert_near(map.move_circle(start, delta, RADIUS), (1.5, 2.5));
+        map.doors[0].state = DoorState::Closing;
+        assert_near(map.move_circle(start, delta, RADIUS), (1.5, 1.0 - RADIUS));
+    }
+
+    #[test]
+    fn move_circle_against_moving_pushwall() {
+        let mut map = WorldMap::from_rows(&[
+            "#######",
+            "#..P..#",
+            "#######",
+        ]);
+        let start = Vector2::new(1.5, 1.5);
+        let delta = Vector2::new(4.0, 0.0);
+        assert!(map.push_wall(3, 1, (1, 0), &[]));
+        // Out of the grid but still solid where it is
+        assert_eq!(map.get_cell(3, 1).wall_tex, 0);
+        assert_near(map.move_circle(start, delta, RADIUS), (3.0 - RADIUS, 1.5));
+        // Mid slide it blocks both cells it straddles
+        map.pushwalls[0].offset = 0.5;
+        assert_near(map.move_circle(start, delta, RADIUS), (3.0 - RADIUS, 1.5));
+        map.pushwalls[0].offset = 1.0;
+        assert_near(map.move_circle(start, delta, RADIUS), (4.0 - RADIUS, 1.5));
+        // Back in the grid once it's arrived
+        map.tick_pushwalls(2.0);
+        assert_near(map.move_circle(start, delta, RADIUS), (5.0 - RADIUS, 1.5));
+    }
+
+    #[test]
+    fn cast_ray_hits_x_side() {
+        let hit = room().cast_ray(Vector2::new(2.5, 2.5), Vector2::new(1.0, 0.0), 64.0).unwrap();
+        assert_eq!(hit.cell, Vector2::new(5, 2));
+        assert_eq!(hit.side, WallSide::X);
+        assert_eq!(hit.dist, 2.5);
+        assert_near(hit.point, (5.0, 2.5));
+        assert!(hit.inner.is_none());
+    }
+
+    #[test]
+    fn cast_ray_hits_y_side() {
+        let hit = room().cast_ray(Vector2::new(2.5, 2.5), Vector2::new(0.0, -1.0), 64.0).unwrap();
+        assert_eq!(hit.cell, Vector2::new(2, 0));
+        assert_eq!(hit.side, WallSide::Y);
+        assert_eq!(hit.dist, 1.5);
+        assert_near(hit.point, (2.5, 1.0));
+    }
+
+    #[test]
+    fn cast_ray_dist_is_in_ray_lengths() {
+        let hit = room().cast_ray(Vector2::new(2.5, 2.5), Vector2::new(2.0, 0.0), 64.0).unwrap();
+        assert_eq!(hit.dist, 1.25);
+        assert_near(hit.point, (5.0, 2.5));
+    }
+
+    #[test]
+    fn cast_ray_max_dist() {
+        let map = room();
+        assert!(map.cast_ray(Vector2::new(2.5, 2.5), Vector2::new(1.0, 0.0), 2.4).is_none());
+        assert!(map.cast_ray(Vector2::new(2.5, 2.5), Vector2::new(1.0, 0.0), 2.5).is_some());
+        // The door cell starts within reach but the slab in its middle doesn't
+        let map = door_corridor();
+        assert!(map.cast_ray(Vector2::new(1.5, 0.5), Vector2::new(0.0, 1.0), 0.75).is_none());
+        assert!(map.cast_ray(Vector2::new(1.5, 0.5), Vector2::new(0.0, 1.0), 1.0).is_some());
+    }
+
+    #[test]
+    fn cast_ray_stops_at_view_distance() {
+        let mut map = WorldMap::from_rows(&[
+            "########",
+            "#......#",
+            "########",
+        ]);
+        let origin = Vector2::new(1.5, 1.5);
+        let hit = map.cast_ray(origin, Vector2::new(1.0, 0.0), map.view_distance).unwrap();
+        assert_eq!(hit.dist, 5.5);
+        map.view_distance = 3.0;
+        assert!(map.cast_ray(origin, Vector2::new(1.0, 0.0), map.view_distance).is_none());
+    }
+
+    #[test]
+    fn cast_ray_leaves_open_map() {
+        let map = WorldMap::from_rows(&[
+            "....",
+            "....",
+            "....",
+        ]);
+        assert!(map.cast_ray(Vector2::new(1.5, 1.5), Vector2::new(1.0, 0.3), 64.0).is_none());
+    }
+
+    #[test]
+    fn cast_ray_hits_door_slab() {
+        let mut map = door_corridor();
+        let origin = Vector2::new(1.5, 0.5);
+        let hit = map.cast_ray(origin, Vector2::new(0.0, 1.0), 64.0).unwrap();
+        assert_eq!(hit.cell, Vector2::new(1, 1));
+        assert_eq!(hit.side, WallSide::Y);
+        assert_eq!(hit.dist, 1.0);
+        assert_near(hit.point, (1.5, 1.5));
+        assert!(hit.inner.is_some());
+
+        // Through the gap of an open door to the wall behind
+        map.doors[0].open_amount = 1.0;
+        let hit = map.cast_ray(origin, Vector2::new(0.0, 1.0), 64.0).unwrap();
+        assert_eq!(hit.cell, Vector2::new(1, 3));
+        assert_eq!(hit.dist, 2.5);
+        assert!(hit.inner.is_none());
+    }
+
+    #[test]
+    fn trace_and_line_of_sight() {
+        let map = WorldMap::from_rows(&[
+            "#####",
+            "#.#.#",
+            "#...#",
+            "#####",
+        ]);
+        assert!(map.line_of_sight(Vector2::new(1.5, 2.5), Vector2::new(3.5, 2.5)));
+        assert!(!map.line_of_sight(Vector2::new(1.5, 1.5), Vector2::new(3.5, 1.5)));
+        let hit = map.trace(Vector2::new(1.5, 1.5), Vector2::new(3.5, 1.5)).unwrap();
+        assert_eq!(hit.cell, Vector2::new(2, 1));
+        assert_near(hit.point, (2.0, 1.5));
+    }
+
+    #[test]
+    fn trace_against_door() {
+        let mut map = door_corridor();
+        let from = Vector2::new(1.5, 0.5);
+        let hit = map.trace(from, Vector2::new(1.5, 2.5)).unwrap();
+        assert_eq!(hit.cell, Vector2::new(1, 1));
+        assert_near(hit.point, (1.5, 1.5));
+        // Up to the slab is still in sight
+        assert!(map.line_of_sight(from, Vector2::new(1.5, 1.25)));
+        map.doors[0].open_amount = 1.0;
+        assert!(map.line_of_sight(from, Vector2::new(1.5, 2.5)));
+    }
+
+    #[test]
+    fn trace_against_pushwall() {
+        let mut map = WorldMap::from_rows(&[
+            "#######",
+            "#..P..#",
+            "#######",
+        ]);
+        let from = Vector2::new(1.5, 1.5);
+        let to = Vector2::new(4.5, 1.5);
+        // Hidden, it's just a wall
+        let hit = map.trace(from, to).unwrap();
+        assert_eq!(hit.cell, Vector2::new(3, 1));
+        assert_near(hit.point, (3.0, 1.5));
+        assert!(hit.inner.is_none());
+
+        // Halfway through its slide
+        assert!(map.push_wall(3, 1, (1, 0), &[]));
+        map.pushwalls[0].offset = 0.5;
+        let hit = map.trace(from, to).unwrap();
+        assert_near(hit.point, (3.5, 1.5));
+        assert!(hit.inner.is_some());
+
+        // Arrived two cells on, leaving a clear view up to it
+        map.tick_pushwalls(2.0);
+        assert!(map.line_of_sight(from, to));
+        let hit = map.trace(from, Vector2::new(5.5, 1.5)).unwrap();
+        assert_eq!(hit.cell, Vector2::new(5, 1));
+        assert_near(hit.point, (5.0, 1.5));
+    }
+
+    // Corridor with a pushwall at (2, 1) and room for it to slide 2 cells along +x
+    fn pushwall_corridor() -> WorldMap {
+        WorldMap::from_rows(&[
+            "#######",
+            "#.P...#",
+            "#######",
+        ])
+    }
+
+    #[test]
+    fn push_wall_into_open_space() {
+        let mut map = pushwall_corridor();
+        assert!(map.push_wall(2, 1, (1, 0), &[]));
+        let pushwall = &map.pushwalls[0];
+        assert_eq!(pushwall.state, PushWallState::Moving);
+        assert_eq!(pushwall.distance, PUSHWALL_DISTANCE);
+        assert_eq!(pushwall.cell_at(pushwall.distance), (4, 1));
+        assert_eq!(map.get_cell(2, 1).wall_tex, 0);
+    }
+
+    #[test]
+    fn push_wall_blocked_first_cell() {
+        let mut map = pushwall_corridor();
+        assert!(!map.push_wall(2, 1, (0, 1), &[]));
+        assert_eq!(map.pushwalls[0].state, PushWallState::Hidden);
+        assert_eq!(map.get_cell(2, 1).wall_tex, 1);
+        assert_eq!(map.secrets_found, 0);
+        // Nothing to push away from the wall either
+        assert!(!map.push_wall(1, 1, (1, 0), &[]));
+    }
+
+    #[test]
+    fn push_wall_stops_before_door() {
+        let mut map = WorldMap::from_rows(&[
+            "######",
+            "#.P.D#",
+            "######",
+        ]);
+        assert!(map.push_wall(2, 1, (1, 0), &[]));
+        assert_eq!(map.pushwalls[0].distance, 1);
+    }
+
+    #[test]
+    fn push_wall_stops_before_occupants() {
+        let mut map = pushwall_corridor();
+        // Standing in the second cell, and only just overlapping it from the first
+        assert!(map.push_wall(2, 1, (1, 0), &[(Vector2::new(4.5, 1.5), 0.25)]));
+        assert_eq!(map.pushwalls[0].distance, 1);
+        let mut map = pushwall_corridor();
+        assert!(!map.push_wall(2, 1, (1, 0), &[(Vector2::new(4.1, 1.5), 0.25)]));
+        assert_eq!(map.pushwalls[0].state, PushWallState::Hidden);
+        assert_eq!(map.secrets_found, 0);
+    }
+
+    #[test]
+    fn push_wall_solid_again_on_arrival() {
+        let mut map = pushwall_corridor();
+        assert!(map.push_wall(2, 1, (1, 0), &[]));
+        map.tick_pushwalls(1.0);
+        assert_eq!(map.pushwalls[0].state, PushWallState::Moving);
+        assert_eq!(map.get_cell(4, 1).wall_tex, 0);
+        map.tick_pushwalls(1.5);
+        assert_eq!(map.pushwalls[0].state, PushWallState::Done);
+        assert_eq!(map.pushwalls[0].offset, 2.0);
+        assert_eq!(map.get_cell(4, 1).wall_tex, 1);
+        assert!(map.is_solid(4, 1));
+        assert!(!map.is_solid(2, 1));
+    }
+
+    #[test]
+    fn push_wall_counts_secret_once() {
+        let mut map = pushwall_corridor();
+        assert_eq!(map.secrets_total, 1);
+        assert!(map.push_wall(2, 1, (1, 0), &[]));
+        assert!(!map.push_wall(2, 1, (1, 0), &[]));
+        map.tick_pushwalls(5.0);
+        assert!(!map.push_wall(4, 1, (-1, 0), &[]));
+        assert_eq!(map.secrets_found, 1);
+    }
 }
@@ -0,0 +1,269 @@
+use std::ops::Index;
+use std::ops::IndexMut;
+
+use crate::sprites::Entity;
+
+// Stable reference to a spawned entity. Stays safe to hold across frames: once the entity is despawned
+// its slot's generation moves on, so the handle stops resolving instead of pointing at whatever reuses the slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EntityHandle {
+    index: u32,
+    generation: u32,
+}
+
+struct Slot {
+    generation: u32,
+    entity: Option<Entity>,
+}
+
+// All live entities in a level. Slots of despawned entities are reused by later spawns
+pub struct EntityStore {
+    slots: Vec<Slot>,
+    free: Vec<u32>, // Empty slots
+    despawn_queue: Vec<EntityHandle>, // Removed at the end of the tick
+}
+
+impl EntityStore {
+    pub fn new() -> EntityStore {
+        EntityStore {
+            slots: vec![],
+            free: vec![],
+            despawn_queue: vec![],
+        }
+    }
+
+    pub fn spawn(&mut self, entity: Entity) -> EntityHandle {
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.entity = Some(entity);
+                return EntityHandle { index: index, generation: slot.generation };
+            },
+            None => {
+                self.slots.push(Slot { generation: 0, entity: Some(entity) });
+                return EntityHandle { index: self.slots.len() as u32 - 1, generation: 0 };
+            },
+        }
+    }
+
+    // Mark an entity for removal. It stays in the world (and its handle stays valid) until flush_despawned,
+    // so nothing being iterated over disappears mid tick
+    pub fn despawn(&mut self, handle: EntityHandle) {
+        if self.contains(handle) && !self.despawn_queue.contains(&handle) {
+            self.despawn_queue.push(handle);
+        }
+    }
+
+    // Remove everything despawned since the last call. Call once at the end of each tick
+    pub fn flush_despawned(&mut self) {
+        for handle in std::mem::take(&mut self.despawn_queue) {
+            let slot = &mut self.slots[handle.index as usize];
+            if slot.generation != handle.generation || slot.entity.is_none() {
+                continue;
+            }
+            slot.entity = None;
+            slot.generation += 1;
+            self.free.push(handle.index);
+        }
+    }
+
+    // Remove everything, invalidating all existing handles
+    pub fn clear(&mut self) {
+        self.free.clear();
+        for (i, slot) in self.slots.iter_mut().enumerate() {
+            slot.entity = None;
+            slot.generation += 1;
+            self.free.push(i as u32);
+        }
+        // Reuse slots in spawn order
+        self.free.reverse();
+        self.despawn_queue.clear();
+    }
+
+    pub fn contains(&self, handle: EntityHandle) -> bool {
+        self.get(handle).is_some()
+    }
+
+    pub fn get(&self, handle: EntityHandle) -> Option<&Entity> {
+        let slot = self.slots.get(handle.index as usize)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        return slot.entity.as_ref();
+    }
+
+    pub fn get_mut(&mut self, handle: EntityHandle) -> Option<&mut Entity> {
+        let slot = self.slots.get_mut(handle.index as usize)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        return slot.entity.as_mut();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Entity> {
+        self.slots.iter().filter_map(|s| s.entity.as_ref())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Entity> {
+        self.slots.iter_mut().filter_map(|s| s.entity.as_mut())
+    }
+
+    // Every live entity along with its handle, without collecting anything
+    pub fn iter_with_handles(&self) -> impl Iterator<Item = (EntityHandle, &Entity)> {
+        self.slots.iter().enumerate().filter_map(|(i, s)| {
+            let handle = EntityHandle { index: i as u32, generation: s.generation };
+            s.entity.as_ref().map(|e| (handle, e))
+        })
+    }
+
+    // Handles of every live entity, for loops that need to change the store while they go
+    pub fn handles(&self) -> Vec<EntityHandle> {
+        self.iter_with_handles().map(|(h, _)| h).collect()
+    }
+
+    // Id given by EntityManager when the entity was created
+    pub fn find_by_id(&self, id: u32) -> Option<EntityHandle> {
+        self.iter_with_handles().find(|(_, e)| e.id == id).map(|(h, _)| h)
+    }
+
+    // Every entity made from the named template
+    pub fn find_by_name<'s>(&'s self, name: &'s str) -> impl Iterator<Item = EntityHandle> + 's {
+        self.find(move |e| e.name == name)
+    }
+
+    pub fn find_by_tag<'s>(&'s self, tag: &'s str) -> impl Iterator<Item = EntityHandle> + 's {
+        self.find(move |e| e.tags.iter().any(|t| t == tag))
+    }
+
+    fn find<'s, F: Fn(&Entity) -> bool + 's>(&'s self, matches: F) -> impl Iterator<Item = EntityHandle> + 's {
+        self.iter_with_handles().filter(move |(_, e)| matches(e)).map(|(h, _)| h)
+    }
+}
+
+// Panics if the handle is stale, use get/get_mut for handles that might be
+impl Index<EntityHandle> for EntityStore {
+    type Output = Entity;
+
+    fn index(&self, handle: EntityHandle) -> &Entity {
+        self.get(handle).expect("stale entity handle")
+    }
+}
+
+impl IndexMut<EntityHandle> for EntityStore {
+    fn index_mut(&mut self, handle: EntityHandle) -> &mut Entity {
+        self.get_mut(handle).expect("stale entity handle")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::damage::Health;
+
+    use cgmath::Vector2;
+    use cgmath::Vector3;
+
+    fn entity(id: u32, name: &str, tags: &[&str]) -> Entity {
+        let sprite = serde_json::from_str(r#"{ "name": "test", "tex_id": "1", "u_scale": 1.0, "v_scale": 1.0, "u_move": 0, "v_move": 0 }"#).unwrap();
+        return Entity {
+            id: id,
+            name: name.to_string(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            sprite: sprite,
+            pos: Vector3::new(0.0, 0.0, 0.0),
+            prev_pos: Vector3::new(0.0, 0.0, 0.0),
+            dir: Vector2::new(1.0, 0.0),
+            collidable: false,
+            collision_radius: 0.0,
+            mass: 0.0,
+            health: Health::new(0.0, 0.0, 0.0),
+            pain_animation: String::new(),
+            death_animation: String::new(),
+            ai: None,
+            projectile: None,
+            pickup: None,
+            animation: None,
+            dead: false,
+        };
+    }
+
+    #[test]
+    fn despawn_waits_for_flush() {
+        let mut store = EntityStore::new();
+        let a = store.spawn(entity(0, "barrel", &[]));
+        let b = store.spawn(entity(1, "barrel", &[]));
+        store.despawn(a);
+        // Still there until the end of the tick
+        assert!(store.contains(a));
+        assert_eq!(store.iter().count(), 2);
+        store.flush_despawned();
+        assert!(!store.contains(a));
+        assert!(store.contains(b));
+        assert_eq!(store.iter().map(|e| e.id).collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn stale_handles_stop_resolving() {
+        let mut store = EntityStore::new();
+        let a = store.spawn(entity(0, "barrel", &[]));
+        store.despawn(a);
+        store.despawn(a);
+        store.flush_despawned();
+        assert!(store.get(a).is_none());
+        assert!(store.get_mut(a).is_none());
+        assert_eq!(store.find_by_id(0), None);
+        // Despawning a stale handle does nothing
+        store.despawn(a);
+        store.flush_despawned();
+        assert_eq!(store.iter().count(), 0);
+    }
+
+    #[test]
+    fn reused_slots_get_a_new_generation() {
+        let mut store = EntityStore::new();
+        let a = store.spawn(entity(0, "barrel", &[]));
+        store.despawn(a);
+        store.flush_despawned();
+        let b = store.spawn(entity(1, "fireball", &[]));
+        assert_eq!(b.index, a.index);
+        assert_eq!(b.generation, a.generation + 1);
+        assert!(store.get(a).is_none());
+        assert_eq!(store[b].id, 1);
+        // An old handle despawned late can't remove the new entity
+        store.despawn(a);
+        store.flush_despawned();
+        assert!(store.contains(b));
+    }
+
+    #[test]
+    fn clear_invalidates_everything() {
+        let mut store = EntityStore::new();
+        let a = store.spawn(entity(0, "barrel", &[]));
+        let b = store.spawn(entity(1, "barrel", &[]));
+        store.despawn(b);
+        store.clear();
+        assert!(!store.contains(a));
+        assert!(!store.contains(b));
+        assert_eq!(store.iter().count(), 0);
+        // Slots are reused in spawn order, and the queued despawn was dropped
+        let c = store.spawn(entity(2, "barrel", &[]));
+        let d = store.spawn(entity(3, "barrel", &[]));
+        assert_eq!((c.index, d.index), (a.index, b.index));
+        store.flush_despawned();
+        assert!(store.contains(c) && store.contains(d));
+    }
+
+    #[test]
+    fn lookups() {
+        let mut store = EntityStore::new();
+        let a = store.spawn(entity(5, "soldier", &["enemy"]));
+        let b = store.spawn(entity(6, "barrel", &[]));
+        let c = store.spawn(entity(7, "soldier", &["enemy", "boss"]));
+        assert_eq!(store.find_by_id(6), Some(b));
+        assert_eq!(store.find_by_id(8), None);
+        assert_eq!(store.find_by_name("soldier").collect::<Vec<_>>(), vec![a, c]);
+        assert_eq!(store.find_by_tag("boss").collect::<Vec<_>>(), vec![c]);
+        assert_eq!(store.iter_with_handles().map(|(h, e)| (h, e.id)).collect::<Vec<_>>(), vec![(a, 5), (b, 6), (c, 7)]);
+        assert_eq!(store.handles(), vec![a, b, c]);
+    }
+}
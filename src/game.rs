@@ -5,23 +5,39 @@ use cgmath::Vector2;
 use cgmath::Vector3;
 use cgmath::InnerSpace;
 
-use sdl2::render::Canvas;
-use sdl2::render::Texture;
 use sdl2::rect::Rect;
-use sdl2::keyboard::Keycode;
 
-use std::collections::HashSet;
+use std::error::Error;
+use std::rc::Rc;
 
 use crate::data::WorldMap;
+use crate::validation::MapAssets;
+use crate::data::WallSide;
 use crate::textures::TextureManager;
 use crate::sprites::Entity;
 use crate::sprites::SpriteManager;
 use crate::sprites::EntityManager;
 use crate::data::EntityJSON;
 use crate::animation::AnimationManager;
+use crate::render::RenderTarget;
+use crate::input::InputManager;
+use crate::input::Action;
+use crate::damage::Health;
+use crate::damage::DamageEvent;
+use crate::damage::DamageTarget;
+use crate::ai::AiState;
+use crate::pathfinding::Pathfinder;
+use crate::entity_store::EntityStore;
+use crate::entity_store::EntityHandle;
+use crate::pickup::Inventory;
+use crate::pathfinding::next_waypoint;
+use crate::pathfinding::steer;
+use crate::ai::AttackType;
+use crate::ai::ATTACK_TIME;
+use crate::config::DEFAULT_WIDTH;
+use crate::config::DEFAULT_HEIGHT;
+use crate::config::DEFAULT_FOV;
 
-const SCREEN_HEIGHT: i32 = 600;
-const SCREEN_WIDTH: i32 = 800;
 const TEX_WIDTH: u32 = 64;
 const TEX_HEIGHT: u32 = 64;
 const WALL_HEIGHT_SCALE: f64 = 1.0;
@@ -30,18 +46,42 @@ const ROT_SPEED: f64 = 2.0;
 const ACCELERATION: f64 = 0.1;
 const DRAG: f64 = 3.0;
 const PLAYER_RADIUS: f64 = 0.2;
+const PLAYER_HEALTH: f64 = 100.0;
+const PLAYER_ARMOUR: f64 = 0.0;
+const DEATH_CAMERA_TIME: f64 = 3.0; // Seconds from death to restarting the level
+const DEATH_TURN_SPEED: f64 = 2.0; // Radians per second the death camera turns towards the killer
+const DEBUG_HURT_DAMAGE: f64 = 25.0;
+const ENTITY_MIN_RADIUS: f64 = 0.2; // Radius used for moving entities that don't collide
+const ENTITY_TURN_SPEED: f64 = 6.0; // Radians per second entities can turn while following a path
+const LOCKED_SOUND: &str = "door_locked"; // Played when using a door without its key
+const PICKUP_RADIUS: f64 = 0.3; // Reach of pickups without a collision radius of their own
+const MAX_PROJECTILES: usize = 64; // Of each kind alive at once
+const PROJECTILE_WALL_GAP: f64 = 0.05; // How far from a wall projectiles burst
+const PATROL_SPEED_SCALE: f64 = 0.5; // Patrolling is slower than chasing
+const PLAYER_MASS: f64 = 2.0; // Compared against entity mass when pushing things
+const USE_DISTANCE: f64 = 1.0;
+const WEAPON_DAMAGE: f64 = 25.0;
+const FIRE_DELAY: f64 = 0.4; // Seconds between shots
+const RUN_MULTIPLIER: f64 = 2.0; // Speed and acceleration while running
+pub const TICK_RATE: f64 = 70.0; // Simulation steps per second
+const MOUSE_TURN_SCALE: f64 = 0.003; // Radians per pixel of mouse motion at sensitivity 1
 
 struct Player {
     pos: Vector3<f64>,
     dir: Vector2<f64>,
+    // State at the start of the last simulation step, for interpolation
+    prev_pos: Vector3<f64>,
+    prev_dir: Vector2<f64>,
     velocity: Vector3<f64>,
     camera_plane: Vector2<f64>,
+    health: Health,
+    inventory: Inventory,
 }
 
-#[derive(PartialEq)]
-enum WallSide {
-    X,
-    Y,
+// Player has died, the camera is turning to face whatever killed them before the level restarts
+struct Death {
+    timer: f64, // Seconds since death
+    killer: Option<Vector2<f64>>,
 }
 
 #[derive(Debug)]
@@ -50,82 +90,295 @@ struct SpriteSortable<'a> {
     distance: f64,
 }
 
-pub struct Game<'a, 'b, 'c> {
+pub struct Game<'a, 'c> {
     player: Player,
     world_map: WorldMap,
-    entities: Vec<Entity>,
+    entities: EntityStore,
     texture_manager: &'a TextureManager<'a>,
     sprite_manager: &'c SpriteManager<'c>,
     entity_manager: &'c mut EntityManager<'c>, // Mutable because it increments itself each time it spawns
     animation_manager: &'c AnimationManager,
-    floor_texture: &'b mut Texture<'a>,
-    z_buffer: [f64; SCREEN_WIDTH as usize],
+    screen_width: i32, // Internal render resolution
+    screen_height: i32,
+    fov: f64, // Horizontal field of view in degrees, for a 4:3 frame
+    z_buffer: Vec<f64>,
+    mouse_sensitivity: f64,
+    invert_mouse: bool,
+    fire_cooldown: f64, // Seconds until the weapon can fire again
+    damage_events: Vec<DamageEvent>, // Since the last take_damage_events
+    death: Option<Death>,
+    restart_pending: bool, // Death camera has finished, the level should be reloaded
+    pathfinder: Pathfinder,
+    messages: Vec<String>, // For the HUD, since the last take_messages
+    sound_events: Vec<String>, // Names of sounds to play, since the last take_sound_events
 }
 
-impl<'a, 'b, 'c> Game<'a, 'b, 'c> {
-    pub fn new(map: WorldMap, manager: &'a TextureManager, s_manager: &'c SpriteManager, e_manager: &'c mut EntityManager<'c>, a_manager: &'c AnimationManager, floor_tex: &'b mut Texture<'a>) -> Game<'a, 'b, 'c> {
-        // Init Player and Camera
-        let player = Player {
-            pos: Vector3::new(6.5, 3.5, 0.0),
-            dir: Vector2::new(-1.0, 0.0),
-            velocity: Vector3::new(0.0, 0.0, 0.0),
-            camera_plane: Vector2::new(0.0, 0.66),
-        };
+impl<'a, 'c> Game<'a, 'c> {
+    pub fn new(map: WorldMap, manager: &'a TextureManager, s_manager: &'c SpriteManager, e_manager: &'c mut EntityManager<'c>, a_manager: &'c AnimationManager) -> Result<Game<'a, 'c>, Box<dyn Error>> {
+        let player = start_player(&map)?;
         let mut g = Game {
             player: player,
             world_map: map,
-            entities: vec![],
+            entities: EntityStore::new(),
             texture_manager: manager,
             sprite_manager: s_manager,
             entity_manager: e_manager,
             animation_manager: a_manager,
-            floor_texture: floor_tex,
-            z_buffer: [0.0; SCREEN_WIDTH as usize],
+            screen_width: DEFAULT_WIDTH as i32,
+            screen_height: DEFAULT_HEIGHT as i32,
+            fov: DEFAULT_FOV,
+            z_buffer: vec![0.0; DEFAULT_WIDTH as usize],
+            mouse_sensitivity: 1.0,
+            invert_mouse: false,
+            fire_cooldown: 0.0,
+            damage_events: vec![],
+            death: None,
+            restart_pending: false,
+            pathfinder: Pathfinder::new(),
+            messages: vec![],
+            sound_events: vec![],
         };
 
+        g.update_camera_plane();
+
         // Spawn all entities defined on the map
         g.init_entities(&g.world_map.entities.clone());
 
-        return g;
+        return Ok(g);
+    }
+
+    // Start the level again on a freshly loaded copy of the map
+    pub fn restart(&mut self, map_name: &str) -> Result<(), Box<dyn Error>> {
+        let assets = MapAssets {
+            textures: self.texture_manager,
+            entities: self.entity_manager,
+            animations: self.animation_manager,
+        };
+        let map = WorldMap::load_map(map_name, &assets)?;
+        self.player = start_player(&map)?;
+        self.world_map = map;
+        self.update_camera_plane();
+        self.entities.clear();
+        self.init_entities(&self.world_map.entities.clone());
+        self.fire_cooldown = 0.0;
+        self.damage_events.clear();
+        self.messages.clear();
+        self.sound_events.clear();
+        self.death = None;
+        self.restart_pending = false;
+        return Ok(());
+    }
+
+    // Advance the world by one fixed simulation step
+    pub fn tick(&mut self, input: &InputManager, dt: f64) {
+        self.player.prev_pos = self.player.pos;
+        self.player.prev_dir = self.player.dir;
+        for e in self.entities.iter_mut() {
+            e.prev_pos = e.pos;
+        }
+        match self.death {
+            None => self.move_player(input, dt),
+            Some(_) => self.tick_death_camera(dt),
+        }
+        self.tick_ai(dt);
+        self.tick_projectiles(dt);
+        self.tick_animations(dt);
+        self.despawn_spent_projectiles();
+        self.tick_doors(dt);
+        self.world_map.tick_pushwalls(dt);
+        self.entities.flush_despawned();
+    }
+
+    // Render the world alpha of the way (0.0 -> 1.0) from the previous simulation step to the current one
+    pub fn draw<T: RenderTarget>(&mut self, target: &mut T, alpha: f64) {
+        // Swap the interpolated states in for rendering, then put the real ones back
+        let pos = self.player.pos;
+        let dir = self.player.dir;
+        let camera_plane = self.player.camera_plane;
+        let entity_pos: Vec<Vector3<f64>> = self.entities.iter().map(|e| e.pos).collect();
+        self.player.pos = self.player.prev_pos + (pos - self.player.prev_pos) * alpha;
+        let lerp_dir = self.player.prev_dir + (dir - self.player.prev_dir) * alpha;
+        if lerp_dir.magnitude() > 0.0 {
+            self.player.dir = lerp_dir.normalize();
+            self.update_camera_plane();
+        }
+        for e in self.entities.iter_mut() {
+            e.pos = e.prev_pos + (e.pos - e.prev_pos) * alpha;
+        }
+
+        self.render_floor(target);
+        self.render_walls(target);
+        self.render_sprites(target);
+
+        self.player.pos = pos;
+        self.player.dir = dir;
+        self.player.camera_plane = camera_plane;
+        for (e, pos) in self.entities.iter_mut().zip(entity_pos) {
+            e.pos = pos;
+        }
+    }
+
+    // Hurt the player. source: where the damage came from, the death camera turns to face it
+    pub fn damage_player(&mut self, amount: f64, source: Option<Vector2<f64>>) {
+        if self.death.is_some() {
+            return;
+        }
+        let lost = self.player.health.apply(amount);
+        let killed = self.player.health.is_dead();
+        self.damage_events.push(DamageEvent {
+            target: DamageTarget::Player,
+            amount: lost,
+            killed: killed,
+        });
+        if killed {
+            self.player.velocity = Vector3::new(0.0, 0.0, 0.0);
+            self.death = Some(Death {
+                timer: 0.0,
+                killer: source,
+            });
+        }
+    }
+
+    // Turn towards the killer, then ask for a restart once the death camera has had its time
+    fn tick_death_camera(&mut self, dt: f64) {
+        let (timer, killer) = match &mut self.death {
+            Some(death) => {
+                death.timer += dt;
+                (death.timer, death.killer)
+            },
+            None => return,
+        };
+        if let Some(killer) = killer {
+            let to_killer = killer - Vector2::new(self.player.pos.x, self.player.pos.y);
+            if to_killer.magnitude() > 0.0 {
+                // Signed angle from the view direction to the killer, positive is to the left
+                let dir = self.player.dir;
+                let angle = (dir.x * to_killer.y - dir.y * to_killer.x).atan2(dir.dot(to_killer));
+                let max_turn = DEATH_TURN_SPEED * dt;
+                self.rotate_player(angle.max(-max_turn).min(max_turn));
+            }
+        }
+        if timer >= DEATH_CAMERA_TIME {
+            self.restart_pending = true;
+        }
+    }
+
+    // Everything that took damage since the last call, for the HUD
+    pub fn take_damage_events(&mut self) -> Vec<DamageEvent> {
+        std::mem::take(&mut self.damage_events)
+    }
+
+    pub fn player_health(&self) -> Health {
+        self.player.health
+    }
+
+    pub fn inventory(&self) -> &Inventory {
+        &self.player.inventory
     }
 
-    pub fn draw(&mut self, canvas: &mut Canvas<sdl2::video::Window>, frame_time: f64) {
-        self.render_floor(canvas);
-        self.render_walls(canvas);
-        self.render_sprites(canvas);
-        self.tick_animations(frame_time);
+    pub fn take_messages(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.messages)
+    }
+
+    pub fn take_sound_events(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.sound_events)
+    }
+
+    // 0.0 while alive, rising to 1.0 as the death camera finishes
+    pub fn death_progress(&self) -> f64 {
+        match &self.death {
+            Some(death) => (death.timer / DEATH_CAMERA_TIME).min(1.0),
+            None => 0.0,
+        }
+    }
+
+    pub fn wants_restart(&self) -> bool {
+        self.restart_pending
+    }
+
+    // Change the internal render resolution, e.g. when the window is resized.
+    // The target drawn onto afterwards must be the same size
+    pub fn set_resolution(&mut self, width: u32, height: u32) {
+        self.screen_width = width as i32;
+        self.screen_height = height as i32;
+        self.z_buffer = vec![0.0; width as usize];
+        self.update_camera_plane();
+    }
+
+    pub fn set_fov(&mut self, fov: f64) {
+        self.fov = fov;
+        self.update_camera_plane();
+    }
+
+    // The fov is what a 4:3 frame shows horizontally. The vertical view stays fixed and wider frames
+    // see more to the sides
+    fn plane_length(&self) -> f64 {
+        return vertical_view(self.fov) * self.screen_width as f64 / self.screen_height as f64;
+    }
+
+    // Screen pixels per world unit at distance 1. screen_height at the default fov like the original
+    // renderer, less for wider fovs
+    fn projection_scale(&self) -> f64 {
+        return self.screen_height as f64 * vertical_view(DEFAULT_FOV) / vertical_view(self.fov);
+    }
+
+    fn update_camera_plane(&mut self) {
+        self.player.camera_plane = camera_plane(self.player.dir, self.plane_length());
+    }
+
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.screen_width as u32, self.screen_height as u32)
+    }
+
+    // Place the camera directly
+    pub fn set_player_pose(&mut self, pos: Vector2<f64>, dir: Vector2<f64>) {
+        let dir = dir.normalize();
+        self.player.pos = Vector3::new(pos.x, pos.y, 0.0);
+        self.player.dir = dir;
+        self.player.prev_pos = self.player.pos;
+        self.player.prev_dir = dir;
+        self.update_camera_plane();
+        self.player.velocity = Vector3::new(0.0, 0.0, 0.0);
     }
 
     // Actually renders the floor AND ceiling
     // Horizontally raycasts
-    fn render_floor(&mut self, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>) {
-        let new_data = &mut vec![128; (SCREEN_WIDTH * SCREEN_HEIGHT * 4) as usize];
+    fn render_floor<T: RenderTarget>(&mut self, target: &mut T) {
+        // Anything not covered by a floor/ceiling texture is left as the void colour
+        let void = self.world_map.outside.color;
+        let new_data = &mut [void[0], void[1], void[2], 255].repeat((self.screen_width * self.screen_height) as usize);
         let left_ray = self.player.dir - self.player.camera_plane;
         let right_ray = self.player.dir + self.player.camera_plane;
-        for y in SCREEN_HEIGHT / 2..SCREEN_HEIGHT {
+        for y in self.screen_height / 2..self.screen_height {
             // Current y distance to middle of screen
-            let p = y - SCREEN_HEIGHT / 2;
+            let p = y - self.screen_height / 2;
             // Height of camera
-            let pos_z = 0.5 * SCREEN_HEIGHT as f64;
+            let pos_z = 0.5 * self.projection_scale();
             // Horizontal distance from camera to floor for current row
             let row_dist = pos_z / p as f64;
+            // Rows past the view distance are left as void
+            if row_dist > self.world_map.view_distance {
+                continue;
+            }
 
-            let floor_step = (right_ray - left_ray) * row_dist / SCREEN_WIDTH as f64;
+            let floor_step = (right_ray - left_ray) * row_dist / self.screen_width as f64;
 
             let mut floor_pos = Vector2::new(
                 self.player.pos.x + row_dist * left_ray.x,
                 self.player.pos.y + row_dist * left_ray.y,
             );
 
-            for x in 0..SCREEN_WIDTH {
-                // Take integer portion for cell #
+            for x in 0..self.screen_width {
+                // Take integer portion for cell # (floor, so positions left/above the map stay negative)
                 let floor_cell = Vector2::new(
-                    floor_pos.x as i32,
-                    floor_pos.y as i32,
+                    floor_pos.x.floor() as i32,
+                    floor_pos.y.floor() as i32,
                 );
 
-                let f_cell = self.world_map.get_cell(floor_cell.x as u32 & (self.world_map.width - 1), floor_cell.y as u32 & (self.world_map.height - 1)).floor_tex - 1;
-                let c_cell = self.world_map.get_cell(floor_cell.x as u32 & (self.world_map.width - 1), floor_cell.y as u32 & (self.world_map.height - 1)).ceil_tex - 1;
+                let (f_cell, c_cell) = match self.world_map.surface_cell(floor_cell.x, floor_cell.y) {
+                    Some(cell) => (cell.floor_tex - 1, cell.ceil_tex - 1),
+                    None => (-1, -1),
+                };
 
                 // Get fractional part of coordiate (how far in cell)
                 let tex_x = (TEX_WIDTH as f64 * (floor_pos.x - floor_cell.x as f64)) as u32 & (TEX_WIDTH - 1);
@@ -139,107 +392,68 @@ impl<'a, 'b, 'c> Game<'a, 'b, 'c> {
                 unsafe {
                     if f_cell > -1 {
                         // Floor
-                        let tex_start = &self.texture_manager.get_raw_tex(f_cell as u32)[((TEX_WIDTH * tex_y + tex_x) * 4) as usize] as *const u8;
-                        let floor_start = &mut new_data[((y * SCREEN_WIDTH + x) * 4) as usize] as *mut u8;
+                        let tex_start = &self.texture_manager.get_raw_tex(f_cell as u32).pixels[((TEX_WIDTH * tex_y + tex_x) * 4) as usize] as *const u8;
+                        let floor_start = &mut new_data[((y * self.screen_width + x) * 4) as usize] as *mut u8;
                         std::ptr::copy(tex_start, floor_start, 4);
                     }
                     if c_cell > -1 {
                         // Ceiling
-                        let tex_start = &self.texture_manager.get_raw_tex(c_cell as u32)[((TEX_WIDTH * tex_y + tex_x) * 4) as usize] as *const u8;
-                        let ceil_start = &mut new_data[(((SCREEN_HEIGHT - y) * SCREEN_WIDTH + x) * 4) as usize] as *mut u8;
+                        let tex_start = &self.texture_manager.get_raw_tex(c_cell as u32).pixels[((TEX_WIDTH * tex_y + tex_x) * 4) as usize] as *const u8;
+                        let ceil_start = &mut new_data[(((self.screen_height - y) * self.screen_width + x) * 4) as usize] as *mut u8;
                         std::ptr::copy(tex_start, ceil_start, 4);
                     }
                 }
             }
         }
-        // Faster than texture.update?
-        self.floor_texture.with_lock(None, |dat, _| {
-            dat.copy_from_slice(new_data);
-        }).unwrap();
-
-        canvas.copy(self.floor_texture, None, None).unwrap();
+        target.draw_buffer(new_data);
     }
 
     // Vertical raycast walls
-    fn render_walls(&mut self, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>) {
-        for i in 0..SCREEN_WIDTH {
+    fn render_walls<T: RenderTarget>(&mut self, target: &mut T) {
+        let player_pos = Vector2::new(self.player.pos.x, self.player.pos.y);
+        for i in 0..self.screen_width {
             // Calculate incoming ray position/direction
-            let camera_x: f64 = 2.0 * i as f64 / SCREEN_WIDTH as f64 - 1.0;
+            let camera_x: f64 = 2.0 * i as f64 / self.screen_width as f64 - 1.0;
             let ray_hit_pos = camera_x * self.player.camera_plane;
             let ray_dir = self.player.dir + ray_hit_pos;
-            // Which box we're in
-            let mut curr_grid = Vector2::new(
-                self.player.pos.x as i32,
-                self.player.pos.y as i32,
-            );
-            // Length of ray from any x/y side to next x/y side
-            let delta_dist = Vector2::new((1.0 / ray_dir.x).abs(), (1.0 / ray_dir.y).abs());
-            let step_x: i32;
-            let step_y: i32;
-            let mut side_dist_x: f64;
-            let mut side_dist_y: f64;
-            if ray_dir.x < 0.0 {
-                step_x = -1;
-                side_dist_x = (self.player.pos.x - curr_grid.x as f64) * delta_dist.x;
-            } else {
-                step_x = 1;
-                side_dist_x = (curr_grid.x as f64 + 1.0 - self.player.pos.x) * delta_dist.x;
-            }
-            if ray_dir.y < 0.0 {
-                step_y = -1;
-                side_dist_y = (self.player.pos.y - curr_grid.y as f64) * delta_dist.y;
-            } else {
-                step_y = 1;
-                side_dist_y = (curr_grid.y as f64 + 1.0 - self.player.pos.y) * delta_dist.y;
-            }
-
-            // start DDA
-            let mut side: WallSide;
-            loop {
-                if side_dist_x < side_dist_y {
-                    side_dist_x += delta_dist.x;
-                    curr_grid.x += step_x;
-                    side = WallSide::X;
-                } else {
-                    side_dist_y += delta_dist.y;
-                    curr_grid.y += step_y;
-                    side = WallSide::Y;
-                }
-                if self.world_map.get_cell(curr_grid.x as u32, curr_grid.y as u32).wall_tex > 0 {
-                    break;
-                }
-            }
-            let mut perp_wall_dist = match side {
-                WallSide::X => {
-                    (curr_grid.x as f64 - self.player.pos.x + (1.0 - step_x as f64) / 2.0) / ray_dir.x
-                }
-                WallSide::Y => {
-                    (curr_grid.y as f64 - self.player.pos.y + (1.0 - step_y as f64) / 2.0) / ray_dir.y
+            let hit = match self.world_map.cast_ray(player_pos, ray_dir, self.world_map.view_distance) {
+                Some(hit) => hit,
+                None => {
+                    // Sprites can still be drawn anywhere in this column
+                    self.z_buffer[i as usize] = std::f64::INFINITY;
+                    continue;
                 }
             };
+            let curr_grid = hit.cell;
+            let side = hit.side;
+            let inner_hit = hit.inner;
+            let mut perp_wall_dist = hit.dist;
             // Clamp minimum distance to avoid overflow
             perp_wall_dist = perp_wall_dist.max(0.001);
             // Save distance in z-buffer
             self.z_buffer[i as usize] = perp_wall_dist;
             // Calculate height of line
             let line_height =
-                (WALL_HEIGHT_SCALE * SCREEN_HEIGHT as f64 / perp_wall_dist) as i32;
+                (WALL_HEIGHT_SCALE * self.projection_scale() / perp_wall_dist) as i32;
             // Get lowest/highest pixel to draw (drawing walls in middle of screen)
-            let mut draw_start = -1 * line_height / 2 + SCREEN_HEIGHT / 2;
+            let mut draw_start = -1 * line_height / 2 + self.screen_height / 2;
             if draw_start < 0 {
                 draw_start = 0;
             }
-            let mut draw_end = line_height / 2 + SCREEN_HEIGHT / 2;
-            if draw_end >= SCREEN_HEIGHT as i32 {
-                draw_end = SCREEN_HEIGHT as i32 - 1;
+            let mut draw_end = line_height / 2 + self.screen_height / 2;
+            if draw_end >= self.screen_height {
+                draw_end = self.screen_height - 1;
             }
             // Texture calculations
-            let tex_num = self.world_map.get_cell(curr_grid.x as u32, curr_grid.y as u32).wall_tex - 1;
+            let tex_num = match inner_hit {
+                Some(hit) => hit.tex - 1,
+                None => self.world_map.get_cell(curr_grid.x as u32, curr_grid.y as u32).wall_tex - 1,
+            };
 
             // Exact x/y coord where it hit
             let wall_x = match side {
-                WallSide::X => self.player.pos.y + perp_wall_dist * ray_dir.y,
-                WallSide::Y => self.player.pos.x + perp_wall_dist * ray_dir.x,
+                WallSide::X => hit.point.y,
+                WallSide::Y => hit.point.x,
             };
             let wall_x = wall_x - wall_x.floor();
 
@@ -251,13 +465,13 @@ impl<'a, 'b, 'c> Game<'a, 'b, 'c> {
             if side == WallSide::Y && ray_dir.y < 0 as f64 {
                 tex_x = TEX_WIDTH - tex_x - 1;
             }
-            let texture = match side {
-                WallSide::X => self.texture_manager.get_tex(tex_num as u32),
-                WallSide::Y => self.texture_manager.get_dark_tex(tex_num as u32),
-            };
+            // Doors and pushwalls aren't aligned to the grid, so they work out their own texture coord
+            if let Some(hit) = inner_hit {
+                tex_x = ((hit.tex_x * TEX_WIDTH as f64) as u32).min(TEX_WIDTH - 1);
+            }
             let mut tex_strip_start = 0;
             let mut tex_strip_height = TEX_HEIGHT as i32;
-            let screen_wall_ratio = SCREEN_HEIGHT as f64 / line_height as f64;
+            let screen_wall_ratio = self.screen_height as f64 / line_height as f64;
             // Trim texture region to only be the portion visible in the viewcreen, if wall > screen height
             if screen_wall_ratio < 1.0 {
                 let tex_y_drawn = (screen_wall_ratio * TEX_HEIGHT as f64) as i32;
@@ -265,16 +479,74 @@ impl<'a, 'b, 'c> Game<'a, 'b, 'c> {
                 tex_strip_start += offset / 2;
                 tex_strip_height -= offset;
             }
-            canvas.copy(
-                texture, 
+            target.copy_wall(
+                self.texture_manager,
+                tex_num as u32,
+                side == WallSide::Y,
                 Rect::new(tex_x as i32, tex_strip_start, 1, tex_strip_height as u32),
-                Rect::new(i as i32, SCREEN_HEIGHT - draw_end, 1, (draw_end - draw_start) as u32),
-            ).unwrap();
+                Rect::new(i as i32, self.screen_height - draw_end, 1, (draw_end - draw_start) as u32),
+            );
+        }
+    }
+
+    // Where an entity's sprite lands on screen: (depth from camera, centre column, width in columns)
+    fn project_entity(&self, sprite: &Entity) -> (f64, i32, i32) {
+        let rel_pos = sprite.pos - self.player.pos;
+        //transform sprite with the inverse camera matrix
+         // [ planeX   dirX ] -1                                       [ dirY      -dirX ]
+        // [               ]       =  1/(planeX*dirY-dirX*planeY) *   [                 ]
+         // [ planeY   dirY ]                                          [ -planeY  planeX ]
+
+        let inv_det = 1.0 / (self.player.camera_plane.x * self.player.dir.y - self.player.dir.x * self.player.camera_plane.y);
+        let transform_x = inv_det * (self.player.dir.y * rel_pos.x - self.player.dir.x * rel_pos.y);
+        let mut transform_y = inv_det * ((-self.player.camera_plane.y) * rel_pos.x + self.player.camera_plane.x * rel_pos.y); // depth of sprite from camera
+        // Clamp transform_y if ~= 0 to prevent overflows
+        if transform_y.abs() < 0.0001 {
+            if transform_y < 0.0 {
+                transform_y = -0.0001;
+            } else {
+                transform_y = 0.0001;
+            }
+        }
+        let sprite_screen_x = ((self.screen_width / 2) as f64 * (1.0 + transform_x / transform_y)) as i32;
+        let sprite_width = ((self.projection_scale() / transform_y) * sprite.sprite.u_scale) as i32;
+        return (transform_y, sprite_screen_x, sprite_width);
+    }
+
+    // Shoot straight ahead through the crosshair. Walls (and doors/pushwalls) stop the shot, otherwise
+    // the nearest living shootable entity whose sprite covers the centre column takes the damage
+    fn fire(&mut self) {
+        let player_pos = Vector2::new(self.player.pos.x, self.player.pos.y);
+        let wall_dist = match self.world_map.cast_ray(player_pos, self.player.dir, self.world_map.view_distance) {
+            Some(hit) => hit.dist,
+            None => self.world_map.view_distance,
+        };
+        let centre = self.screen_width / 2;
+        let mut target: Option<(EntityHandle, f64)> = None;
+        for handle in self.entities.handles() {
+            let e = &self.entities[handle];
+            if e.dead || e.health.max_health <= 0.0 {
+                continue;
+            }
+            let (depth, screen_x, width) = self.project_entity(e);
+            if depth <= 0.0 || depth >= wall_dist {
+                continue;
+            }
+            if centre < screen_x - width / 2 || centre >= screen_x + width / 2 {
+                continue;
+            }
+            if target.map_or(true, |(_, d)| depth < d) {
+                target = Some((handle, depth));
+            }
+        }
+        if let Some((handle, _)) = target {
+            let event = self.entities[handle].damage(WEAPON_DAMAGE, self.animation_manager);
+            self.damage_events.push(event);
         }
     }
 
     // Render all current "Entities" as 2d sprites
-    fn render_sprites(&mut self, canvas: &mut Canvas<sdl2::video::Window>) {
+    fn render_sprites<T: RenderTarget>(&mut self, target: &mut T) {
         // Get all entities' sprites and sort them
         let mut sprite_buffer = vec![];
         for ent in self.entities.iter() {
@@ -289,33 +561,16 @@ impl<'a, 'b, 'c> Game<'a, 'b, 'c> {
         // draw all sprites
         for s in sprite_buffer {
             let sprite = s.entity;
-            let rel_pos = sprite.pos - self.player.pos;
-            //transform sprite with the inverse camera matrix
-             // [ planeX   dirX ] -1                                       [ dirY      -dirX ]
-            // [               ]       =  1/(planeX*dirY-dirX*planeY) *   [                 ]
-             // [ planeY   dirY ]                                          [ -planeY  planeX ]
-            
-            let inv_det = 1.0 / (self.player.camera_plane.x * self.player.dir.y - self.player.dir.x * self.player.camera_plane.y);
-            let transform_x = inv_det * (self.player.dir.y * rel_pos.x - self.player.dir.x * rel_pos.y);
-            let mut transform_y = inv_det * ((-self.player.camera_plane.y) * rel_pos.x + self.player.camera_plane.x * rel_pos.y); // depth of sprite from camera
-            // Clamp transform_y if ~= 0 to prevent overflows
-            if transform_y.abs() < 0.0001 {
-                if transform_y < 0.0 {
-                    transform_y = -0.0001;
-                } else {
-                    transform_y = 0.0001;
-                }
-            }
+            let (transform_y, sprite_screen_x, sprite_width) = self.project_entity(sprite);
 
-            let mov_screen = (sprite.sprite.v_move as f64 / transform_y) as i32; // User defined sprite offset
-            let sprite_screen_x = ((SCREEN_WIDTH / 2) as f64 * (1.0 + transform_x / transform_y)) as i32;
+            // User defined sprite offset, in pixels at the default resolution
+            let mov_screen = (sprite.sprite.v_move as f64 * self.projection_scale() / DEFAULT_HEIGHT as f64 / transform_y) as i32;
 
             // height of sprite on screen
-            let sprite_height = (((SCREEN_HEIGHT as f64 / transform_y) * sprite.sprite.v_scale) as i32).abs();
-            let sprite_width = ((SCREEN_HEIGHT as f64 / transform_y) * sprite.sprite.u_scale) as i32;
+            let sprite_height = (((self.projection_scale() / transform_y) * sprite.sprite.v_scale) as i32).abs();
             // clamp draw start into screen with max/min
-            let draw_start = Vector2::new(((-sprite_width) / 2 + sprite_screen_x).max(0), ((-sprite_height) / 2 + SCREEN_HEIGHT / 2 + mov_screen).max(0));
-            let draw_end = Vector2::new((sprite_width / 2 + sprite_screen_x).min(SCREEN_WIDTH - 1), (sprite_height / 2 + SCREEN_HEIGHT / 2 + mov_screen).min(SCREEN_HEIGHT - 1));
+            let draw_start = Vector2::new(((-sprite_width) / 2 + sprite_screen_x).max(0), ((-sprite_height) / 2 + self.screen_height / 2 + mov_screen).max(0));
+            let draw_end = Vector2::new((sprite_width / 2 + sprite_screen_x).min(self.screen_width - 1), (sprite_height / 2 + self.screen_height / 2 + mov_screen).min(self.screen_height - 1));
             // Draw every vertical stripe of sprite
             for x in draw_start.x..draw_end.x {
                 let mut angle = 0.0;
@@ -339,12 +594,13 @@ impl<'a, 'b, 'c> Game<'a, 'b, 'c> {
                 //2) it's on the screen (left)
                 //3) it's on the screen (right)
                 //4) ZBuffer, with perpendicular distance
-                if transform_y > 0.0 && x > 0 && x < SCREEN_WIDTH && transform_y < self.z_buffer[x as usize] {
-                    canvas.copy(
-                        self.sprite_manager.get_texture(&sprite.sprite.tex_id).unwrap(),
+                if transform_y > 0.0 && x > 0 && x < self.screen_width && transform_y < self.z_buffer[x as usize] && transform_y < self.world_map.view_distance {
+                    target.copy_sprite(
+                        self.sprite_manager,
+                        &sprite.sprite.tex_id,
                         sprite_rect,
-                        Rect::new(x, SCREEN_HEIGHT - (draw_end.y + mov_screen), 1, sprite_height as u32)
-                    ).unwrap();
+                        Rect::new(x, self.screen_height - (draw_end.y + mov_screen), 1, sprite_height as u32)
+                    );
                 }
             }
         }
@@ -356,106 +612,220 @@ impl<'a, 'b, 'c> Game<'a, 'b, 'c> {
         }
     }
 
+    // Move/turn the player and camera from this step's actions
     pub fn move_player(
         &mut self,
-        event_pump: &sdl2::EventPump,
+        input: &InputManager,
         frame_time: f64,
     ) {
         let rot_speed = frame_time * ROT_SPEED;
-        let pressed_keys: HashSet<Keycode> = event_pump
-            .keyboard_state()
-            .pressed_scancodes()
-            .filter_map(Keycode::from_scancode)
-            .collect();
-        if pressed_keys.contains(&Keycode::Up) {
-            let dir_normal = self.player.dir.normalize();
-            let dir = Vector3::new(dir_normal.x, dir_normal.y, 0.0);
-            let new_velocity = self.player.velocity + ACCELERATION * frame_time * dir;
-            if new_velocity.magnitude() < MOVE_SPEED * frame_time {
+        let (acceleration, max_speed) = match input.pressed(Action::Run) {
+            true => (ACCELERATION * RUN_MULTIPLIER, MOVE_SPEED * RUN_MULTIPLIER),
+            false => (ACCELERATION, MOVE_SPEED),
+        };
+        // Direction the player wants to move in, relative to where they're facing.
+        // Its length is how hard they're pushing (analog sticks), at most 1
+        let forward = self.player.dir.normalize();
+        let right = self.player.camera_plane.normalize();
+        let mut wish_dir = forward * (input.value(Action::Forward) - input.value(Action::Back))
+            + right * (input.value(Action::StrafeRight) - input.value(Action::StrafeLeft));
+        if wish_dir.magnitude() > 1.0 {
+            // Diagonals are no faster than straight lines
+            wish_dir = wish_dir.normalize();
+        }
+        if wish_dir.magnitude() > 0.0 {
+            let dir = Vector3::new(wish_dir.x, wish_dir.y, 0.0);
+            let new_velocity = self.player.velocity + acceleration * frame_time * dir;
+            if new_velocity.magnitude() < max_speed * frame_time {
                 self.player.velocity = new_velocity;
             }
         }
-        if pressed_keys.contains(&Keycode::Down) {
-            let dir_normal = self.player.dir.normalize();
-            let dir = Vector3::new(dir_normal.x, dir_normal.y, 0.0);
-            let new_velocity = self.player.velocity - ACCELERATION * frame_time * dir;
-            if new_velocity.magnitude() < MOVE_SPEED * frame_time {
-                self.player.velocity = new_velocity;
+        let turn = input.value(Action::TurnLeft) - input.value(Action::TurnRight);
+        if turn != 0.0 {
+            self.rotate_player(rot_speed * turn);
+        }
+        // Mouse to the right turns right, like turn_right
+        if input.mouse_dx != 0 {
+            let mut angle = -input.mouse_dx as f64 * MOUSE_TURN_SCALE * self.mouse_sensitivity;
+            if self.invert_mouse {
+                angle = -angle;
             }
+            self.rotate_player(angle);
         }
-        if pressed_keys.contains(&Keycode::Left) {
-            self.player.dir = Vector2::new(
-                self.player.dir.x * rot_speed.cos() - self.player.dir.y * rot_speed.sin(),
-                self.player.dir.x * rot_speed.sin() + self.player.dir.y * rot_speed.cos(),
-            );
-            self.player.camera_plane = Vector2::new(
-                self.player.camera_plane.x * rot_speed.cos() - self.player.camera_plane.y * rot_speed.sin(),
-                self.player.camera_plane.x * rot_speed.sin() + self.player.camera_plane.y * rot_speed.cos(),
-            );
+
+        // Use whatever is in front of the player (only once per press)
+        if input.just_pressed(Action::Use) {
+            self.use_facing();
         }
-        if pressed_keys.contains(&Keycode::Right) {
-            self.player.dir = Vector2::new(
-                self.player.dir.x * (-rot_speed).cos() - self.player.dir.y * (-rot_speed).sin(),
-                self.player.dir.x * (-rot_speed).sin() + self.player.dir.y * (-rot_speed).cos(),
-            );
-            self.player.camera_plane = Vector2::new(
-                self.player.camera_plane.x * (-rot_speed).cos()
-                    - self.player.camera_plane.y * (-rot_speed).sin(),
-                self.player.camera_plane.x * (-rot_speed).sin()
-                    + self.player.camera_plane.y * (-rot_speed).cos(),
-            );
+        // Holding fire keeps shooting at the weapon's rate
+        self.fire_cooldown = (self.fire_cooldown - frame_time).max(0.0);
+        if input.pressed(Action::Fire) && self.fire_cooldown == 0.0 {
+            self.fire();
+            self.fire_cooldown = FIRE_DELAY;
         }
 
         // TODO: remove, just demo'ing animations
-        if pressed_keys.contains(&Keycode::K) {
+        if input.pressed(Action::DebugKill) {
             for e in self.entities.iter_mut() {
                 e.kill(self.animation_manager);
             }
         }
-        if pressed_keys.contains(&Keycode::R) {
+        if input.pressed(Action::DebugRevive) {
             for e in self.entities.iter_mut() {
                 e.revive();
             }
         }
+        if input.just_pressed(Action::DebugHurt) {
+            self.damage_player(DEBUG_HURT_DAMAGE, None);
+        }
 
         // Apply drag
         self.player.velocity -= self.player.velocity * DRAG * frame_time;
-        // Do sprite collision detection
-        for e in self.entities.iter() {
-            if e.collidable {
-                let diff = e.pos - self.player.pos;
-                if diff.magnitude() < e.collision_radius {
-                    self.player.velocity = -1.0 * self.player.velocity;
+        // Move, sliding along walls and around entities (pushing the pushable ones).
+        // Small steps so nothing can be passed through in one go. Velocity keeps only the part that wasn't blocked
+        let old_pos = Vector2::new(self.player.pos.x, self.player.pos.y);
+        let delta = Vector2::new(self.player.velocity.x, self.player.velocity.y);
+        let steps = (delta.magnitude() / (PLAYER_RADIUS * 0.5)).ceil().max(1.0);
+        let mut new_pos = old_pos;
+        for _ in 0..steps as u32 {
+            new_pos = self.world_map.move_circle(new_pos, delta / steps, PLAYER_RADIUS);
+            new_pos = self.push_out_of_entities(new_pos);
+            self.collect_pickups(new_pos);
+        }
+        self.player.pos = Vector3::new(new_pos.x, new_pos.y, self.player.pos.z);
+        self.player.velocity = Vector3::new(new_pos.x - old_pos.x, new_pos.y - old_pos.y, 0.0);
+    }
+
+    // Separate the player (at pos) from every collidable entity it overlaps. Pushable entities take part of the
+    // separation depending on their mass, the player takes the rest. Returns the player's new position
+    fn push_out_of_entities(&mut self, pos: Vector2<f64>) -> Vector2<f64> {
+        let mut pos = pos;
+        for handle in self.entities.handles() {
+            let e = &self.entities[handle];
+            if !e.collidable || e.dead {
+                continue;
+            }
+            let e_pos = Vector2::new(e.pos.x, e.pos.y);
+            let diff = pos - e_pos;
+            let dist = diff.magnitude();
+            let min_dist = PLAYER_RADIUS + e.collision_radius;
+            if dist >= min_dist {
+                continue;
+            }
+            // Exactly on top of each other, pick any direction
+            let normal = match dist > 0.0 {
+                true => diff / dist,
+                false => Vector2::new(1.0, 0.0),
+            };
+            let overlap = min_dist - dist;
+            let mut player_share = overlap;
+            if e.mass > 0.0 {
+                let push = overlap * PLAYER_MASS / (PLAYER_MASS + e.mass);
+                let radius = e.collision_radius;
+                let mut new_e_pos = self.world_map.move_circle(e_pos, -normal * push, radius);
+                // Entities don't get shoved into each other, the push is refused instead
+                if self.pushed_into_entity(handle, e_pos, new_e_pos, radius) {
+                    new_e_pos = e_pos;
                 }
+                let e = &mut self.entities[handle];
+                e.pos = Vector3::new(new_e_pos.x, new_e_pos.y, e.pos.z);
+                // Whatever a wall or another entity stopped the entity moving, the player has to give way instead
+                player_share = min_dist - (pos - new_e_pos).magnitude();
             }
+            pos = self.world_map.push_out_circle(pos + normal * player_share.max(0.0), PLAYER_RADIUS);
         }
-        // Do wall collision detection
-        if self.player.velocity.magnitude() > 0.0 {
-            // Draw a line from current -> direction * hitbox radius
-            let collision_point = self.player.pos + self.player.velocity.normalize() * PLAYER_RADIUS;
-            // Do wall collision detection
-            // Move player based on current velocity
-            if self.world_map.get_cell(collision_point.x as u32, collision_point.y as u32).wall_tex == 0 {
-                // noop
-            } else {
-                // Compare current/new cells, update velocity according to which way we hit the wall
-                let new_x = collision_point.x as u32;
-                let curr_x = self.player.pos.x as u32;
-                // We hit a wall from the x direction
-                if curr_x != new_x {
-                    self.player.velocity = Vector3::new(0.0, self.player.velocity.y, 0.0);
-                } else {
-                    // we hit it from the y direction
-                    self.player.velocity = Vector3::new(self.player.velocity.x, 0.0, 0.0);
-                }
+        return pos;
+    }
+
+    // Whether moving entity `pushed` (of radius) from `from` to `to` would take it further into another collidable
+    // entity. Ones it already overlaps can still be moved away from
+    fn pushed_into_entity(&self, pushed: EntityHandle, from: Vector2<f64>, to: Vector2<f64>, radius: f64) -> bool {
+        return self.entities.iter_with_handles()
+            .filter(|(h, e)| *h != pushed && e.collidable && !e.dead && e.projectile.is_none())
+            .any(|(_, e)| {
+                let other = Vector2::new(e.pos.x, e.pos.y);
+                let dist = (to - other).magnitude();
+                dist < radius + e.collision_radius && dist < (from - other).magnitude()
+            });
+    }
+
+    // Pick up everything the player (at pos) is touching that would be of any use
+    fn collect_pickups(&mut self, pos: Vector2<f64>) {
+        for handle in self.entities.handles() {
+            let e = &mut self.entities[handle];
+            if e.dead || e.pickup.is_none() {
+                continue;
+            }
+            let reach = PLAYER_RADIUS + e.collision_radius.max(PICKUP_RADIUS);
+            if (Vector2::new(e.pos.x, e.pos.y) - pos).magnitude() >= reach {
+                continue;
+            }
+            // Taken out so it can't be collected again before the despawn happens
+            let pickup = e.pickup.take().unwrap();
+            if !self.player.inventory.collect(&pickup.effect, &mut self.player.health) {
+                e.pickup = Some(pickup);
+                continue;
+            }
+            if !pickup.message.is_empty() {
+                self.messages.push(pickup.message);
+            }
+            if !pickup.sound.is_empty() {
+                self.sound_events.push(pickup.sound);
+            }
+            self.entities.despawn(handle);
+        }
+    }
+
+    // Activate the cell directly in front of the player
+    fn use_facing(&mut self) {
+        let target = self.player.pos + USE_DISTANCE * Vector3::new(self.player.dir.x, self.player.dir.y, 0.0).normalize();
+        if let Some(door) = self.world_map.get_door_mut(target.x as i32, target.y as i32) {
+            match door.lock {
+                Some(colour) if !self.player.inventory.has_key(colour) => {
+                    self.messages.push(format!("you need the {} key", colour.name()));
+                    self.sound_events.push(String::from(LOCKED_SOUND));
+                },
+                _ => door.activate(),
             }
+            return;
         }
+        // Pushwalls slide along whichever axis the player is facing most
+        let step = match self.player.dir.x.abs() > self.player.dir.y.abs() {
+            true => (self.player.dir.x.signum() as i32, 0),
+            false => (0, self.player.dir.y.signum() as i32),
+        };
+        let mut occupants = vec![(Vector2::new(self.player.pos.x, self.player.pos.y), PLAYER_RADIUS)];
+        for e in self.entities.iter() {
+            if e.collidable && !e.dead {
+                occupants.push((Vector2::new(e.pos.x, e.pos.y), e.collision_radius));
+            }
+        }
+        self.world_map.push_wall(target.x as i32, target.y as i32, step, &occupants);
+    }
 
-        let new_pos = self.player.pos + self.player.velocity;
-        self.player.pos = new_pos;
+    pub fn entities(&self) -> &EntityStore {
+        &self.entities
     }
 
-    pub fn spawn_entity(&mut self, e: &EntityJSON) {
+    // (secrets found, total secrets) on this level
+    pub fn secrets(&self) -> (u32, u32) {
+        (self.world_map.secrets_found, self.world_map.secrets_total)
+    }
+
+    // Slide doors open/closed. Doors can't close on the player or a collidable entity
+    pub fn tick_doors(&mut self, frame_time: f64) {
+        let mut occupied = vec![(self.player.pos.x as u32, self.player.pos.y as u32)];
+        for e in self.entities.iter() {
+            if e.collidable {
+                occupied.push((e.pos.x as u32, e.pos.y as u32));
+            }
+        }
+        for door in self.world_map.doors.iter_mut() {
+            door.tick(frame_time, occupied.contains(&(door.x, door.y)));
+        }
+    }
+
+    pub fn spawn_entity(&mut self, e: &EntityJSON) -> EntityHandle {
         let mut ent = self.entity_manager.create_entity(&e.name).unwrap();
         let animation = match e.animation.as_str() {
             "" => None,
@@ -463,9 +833,247 @@ impl<'a, 'b, 'c> Game<'a, 'b, 'c> {
         };
         ent.animation = animation;
         ent.pos = Vector3::new(e.x, e.y, 0.0);
+        ent.prev_pos = ent.pos;
         ent.dir = Vector2::new(e.dir_x, e.dir_y);
+        ent.tags.extend(e.tags.iter().cloned());
+        // AI starting state's animation wins over the map's
+        let start = ent.ai.as_ref().filter(|ai| ai.animation(ai.state).is_some()).map(|ai| ai.state);
+        if let Some(state) = start {
+            ent.set_ai_state(state, 0.0, self.animation_manager);
+        }
+
+        return self.entities.spawn(ent);
+    }
 
-        self.entities.push(ent);
+    // Launch a projectile entity from pos towards dir. None if name isn't a projectile,
+    // or there are already too many of them in flight
+    pub fn spawn_projectile(&mut self, name: &str, pos: Vector2<f64>, dir: Vector2<f64>, owner: DamageTarget) -> Option<EntityHandle> {
+        if dir.magnitude2() == 0.0 || self.entities.find_by_name(name).count() >= MAX_PROJECTILES {
+            return None;
+        }
+        let mut ent = match self.entity_manager.create_entity(name) {
+            Some(e) if e.projectile.is_some() => e,
+            _ => return None,
+        };
+        let dir = dir.normalize();
+        ent.pos = Vector3::new(pos.x, pos.y, 0.0);
+        ent.prev_pos = ent.pos;
+        ent.dir = dir;
+        if let Some(p) = &mut ent.projectile {
+            p.velocity = dir * p.speed;
+            p.owner = owner;
+        }
+        return Some(self.entities.spawn(ent));
+    }
+
+    // Fly projectiles, bursting them on walls, entities, the player or when they run out of time
+    fn tick_projectiles(&mut self, dt: f64) {
+        let player_pos = Vector2::new(self.player.pos.x, self.player.pos.y);
+        for handle in self.entities.handles() {
+            let (velocity, radius, damage, owner) = match &mut self.entities[handle].projectile {
+                Some(p) if !p.exploded => {
+                    p.lifetime -= dt;
+                    (p.velocity, p.radius, p.damage, p.owner)
+                },
+                _ => continue,
+            };
+            let expired = self.entities[handle].projectile.as_ref().map_or(false, |p| p.lifetime <= 0.0);
+            let pos = Vector2::new(self.entities[handle].pos.x, self.entities[handle].pos.y);
+            let mut new_pos = pos + velocity * dt;
+            let mut hit = expired;
+            if let Some(wall) = self.world_map.trace(pos, new_pos) {
+                // Stop just short of the wall so the burst isn't drawn inside it
+                new_pos = wall.point - velocity.normalize() * PROJECTILE_WALL_GAP;
+                hit = true;
+            }
+
+            // Nearest entity it's touching, other than itself, its owner and other projectiles
+            let target = self.entities.iter_with_handles()
+                .filter(|(h, _)| *h != handle)
+                .filter(|(_, e)| e.collidable && !e.dead && e.projectile.is_none())
+                .filter(|(_, e)| owner != DamageTarget::Entity(e.id))
+                .map(|(h, e)| (h, (Vector2::new(e.pos.x, e.pos.y) - new_pos).magnitude() - e.collision_radius))
+                .filter(|(_, d)| *d < radius)
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+            let hits_player = owner != DamageTarget::Player && self.death.is_none()
+                && (player_pos - new_pos).magnitude() < PLAYER_RADIUS + radius;
+            if !expired {
+                if let Some((target, _)) = target {
+                    let event = self.entities[target].damage(damage, self.animation_manager);
+                    self.damage_events.push(event);
+                    hit = true;
+                } else if hits_player {
+                    // Death camera turns to face whoever fired it, or where it came from if they're gone
+                    let source = match owner {
+                        DamageTarget::Entity(id) => self.entities.find_by_id(id).map(|h| &self.entities[h].pos),
+                        DamageTarget::Player => None,
+                    };
+                    let source = source.map_or(pos - velocity, |p| Vector2::new(p.x, p.y));
+                    self.damage_player(damage, Some(source));
+                    hit = true;
+                }
+            }
+
+            let e = &mut self.entities[handle];
+            e.pos = Vector3::new(new_pos.x, new_pos.y, e.pos.z);
+            if hit {
+                if let Some(p) = &mut e.projectile {
+                    p.exploded = true;
+                    p.velocity = Vector2::new(0.0, 0.0);
+                    e.animation = match p.impact_animation.as_str() {
+                        "" => None,
+                        name => self.animation_manager.get_animation(name),
+                    };
+                }
+            }
+        }
+    }
+
+    // Despawn projectiles that have burst once their impact animation has finished
+    fn despawn_spent_projectiles(&mut self) {
+        for handle in self.entities.handles() {
+            let e = &self.entities[handle];
+            if e.projectile.as_ref().map_or(false, |p| p.exploded) && e.animation.is_none() {
+                self.entities.despawn(handle);
+            }
+        }
+    }
+
+    // Run every entity's AI for one step
+    fn tick_ai(&mut self, dt: f64) {
+        let player_pos = Vector2::new(self.player.pos.x, self.player.pos.y);
+        let player_alive = self.death.is_none();
+        let occupied = self.entities.iter()
+            .filter(|e| e.collidable && !e.dead)
+            .map(|e| (e.pos.x.floor() as i32, e.pos.y.floor() as i32))
+            .collect();
+        self.pathfinder.begin_frame(occupied);
+        for handle in self.entities.handles() {
+            let (tmpl, state, timer) = match &self.entities[handle].ai {
+                Some(ai) if !self.entities[handle].dead => (Rc::clone(&ai.tmpl), ai.state, ai.timer - dt),
+                _ => continue,
+            };
+            let e = &self.entities[handle];
+            let pos = Vector2::new(e.pos.x, e.pos.y);
+            let radius = e.collision_radius.max(ENTITY_MIN_RADIUS);
+            let to_player = player_pos - pos;
+            let dist = to_player.magnitude();
+            let sees_player = player_alive && dist <= tmpl.sight_range && self.world_map.line_of_sight(pos, player_pos);
+            let in_range = match tmpl.attack {
+                AttackType::Hitscan | AttackType::Projectile => dist <= tmpl.attack_range && sees_player,
+                AttackType::Melee => dist <= tmpl.attack_range + radius + PLAYER_RADIUS,
+            };
+
+            let mut next: Option<(AiState, f64)> = None;
+            let mut walk: Option<Vector2<f64>> = None;
+            let mut face_player = false;
+            let mut steer_dir: Option<Vector2<f64>> = None;
+            let mut attack = false;
+            match state {
+                AiState::Idle | AiState::Patrol => {
+                    if sees_player {
+                        next = Some((AiState::Alert, tmpl.reaction_time));
+                        face_player = true;
+                    } else if state == AiState::Patrol && e.dir.magnitude() > 0.0 {
+                        walk = Some(e.dir.normalize() * tmpl.speed * PATROL_SPEED_SCALE * dt);
+                    }
+                },
+                AiState::Alert => {
+                    face_player = true;
+                    if timer <= 0.0 {
+                        next = Some((AiState::Chase, 0.0));
+                    }
+                },
+                AiState::Chase => {
+                    face_player = in_range;
+                    let cooled_down = e.ai.as_ref().map_or(false, |ai| ai.cooldown <= 0.0);
+                    if !player_alive {
+                        next = Some((tmpl.start, 0.0));
+                    } else if in_range && cooled_down {
+                        next = Some((AiState::Attack, ATTACK_TIME));
+                        attack = true;
+                    } else if !in_range && dist > 0.0 {
+                        // Follow a path round walls, or wait facing the player if there isn't one
+                        match self.pathfinder.find_path(&self.world_map, pos, player_pos) {
+                            Some(path) => {
+                                let waypoint = match path.is_empty() {
+                                    true => player_pos,
+                                    false => next_waypoint(&self.world_map, pos, &path, player_pos),
+                                };
+                                let to_waypoint = waypoint - pos;
+                                if to_waypoint.magnitude2() > 0.0 {
+                                    let dir = steer(e.dir, to_waypoint.normalize(), ENTITY_TURN_SPEED * dt);
+                                    walk = Some(dir * tmpl.speed * dt);
+                                    steer_dir = Some(dir);
+                                }
+                            },
+                            None => face_player = true,
+                        }
+                    }
+                },
+                AiState::Attack | AiState::Pain => {
+                    if timer <= 0.0 {
+                        next = Some((AiState::Chase, 0.0));
+                    }
+                },
+                AiState::Dead => {},
+            }
+
+            if attack {
+                match tmpl.attack {
+                    AttackType::Projectile => {
+                        let id = self.entities[handle].id;
+                        self.spawn_projectile(&tmpl.projectile, pos, to_player, DamageTarget::Entity(id));
+                    },
+                    _ => self.damage_player(tmpl.attack_damage, Some(pos)),
+                }
+            }
+            let e = &mut self.entities[handle];
+            if let Some(ai) = &mut e.ai {
+                ai.timer = timer;
+                ai.cooldown -= dt;
+                if attack {
+                    ai.cooldown = tmpl.attack_cooldown;
+                }
+            }
+            if face_player && dist > 0.0 {
+                e.dir = to_player / dist;
+            }
+            if let Some(dir) = steer_dir {
+                e.dir = dir;
+            }
+            if let Some(delta) = walk {
+                // Keep out of the player, move_player does the rest of the separation
+                let new_pos = self.world_map.move_circle(pos, delta, radius);
+                if (new_pos - player_pos).magnitude() >= radius + PLAYER_RADIUS || (new_pos - player_pos).magnitude() > dist {
+                    e.pos = Vector3::new(new_pos.x, new_pos.y, e.pos.z);
+                }
+                // Patrols turn back when they walk into something
+                if state == AiState::Patrol && (new_pos - pos).magnitude() < delta.magnitude() * 0.5 {
+                    e.dir = -e.dir;
+                }
+            }
+            if let Some((state, timer)) = next {
+                e.set_ai_state(state, timer, self.animation_manager);
+            }
+        }
+    }
+
+    // Turn the player and camera by angle radians, positive is to the left
+    fn rotate_player(&mut self, angle: f64) {
+        self.player.dir = Vector2::new(
+            self.player.dir.x * angle.cos() - self.player.dir.y * angle.sin(),
+            self.player.dir.x * angle.sin() + self.player.dir.y * angle.cos(),
+        );
+        self.player.camera_plane = Vector2::new(
+            self.player.camera_plane.x * angle.cos() - self.player.camera_plane.y * angle.sin(),
+            self.player.camera_plane.x * angle.sin() + self.player.camera_plane.y * angle.cos(),
+        );
+    }
+
+    pub fn set_mouse_look(&mut self, sensitivity: f64, invert: bool) {
+        self.mouse_sensitivity = sensitivity;
+        self.invert_mouse = invert;
     }
 
     pub fn init_entities(&mut self, ents: &Vec<EntityJSON>) {
@@ -473,4 +1081,523 @@ impl<'a, 'b, 'c> Game<'a, 'b, 'c> {
             self.spawn_entity(&e);
         }
     }
-}
\ No newline at end of file
+}
+
+// Player at the map's first player start, with full health
+fn start_player(map: &WorldMap) -> Result<Player, Box<dyn Error>> {
+    let start = match map.player_starts.first() {
+        Some(s) => s.clone(),
+        None => return Err("map has no player start".into()),
+    };
+    if map.is_solid(start.x as i32, start.y as i32) {
+        return Err(format!("player start at ({}, {}) is inside a solid cell", start.x, start.y).into());
+    }
+    let dir = Vector2::new(start.dir_x, start.dir_y);
+    if dir.magnitude() == 0.0 {
+        return Err(format!("player start at ({}, {}) has no direction", start.x, start.y).into());
+    }
+    let dir = dir.normalize();
+    return Ok(Player {
+        pos: Vector3::new(start.x, start.y, 0.0),
+        dir: dir,
+        prev_pos: Vector3::new(start.x, start.y, 0.0),
+        prev_dir: dir,
+        velocity: Vector3::new(0.0, 0.0, 0.0),
+        camera_plane: camera_plane(dir, 1.0), // Sized properly once the resolution is known
+        health: Health::new(PLAYER_HEALTH, PLAYER_ARMOUR, 0.0),
+        inventory: Inventory::new(),
+    });
+}
+
+// Camera plane is perpendicular to the (normalized) view direction, to the right of it
+fn camera_plane(dir: Vector2<f64>, length: f64) -> Vector2<f64> {
+    Vector2::new(dir.y, -dir.x) * length
+}
+
+// Half the vertical view at distance 1, for a horizontal fov (degrees) on a 4:3 frame
+fn vertical_view(fov: f64) -> f64 {
+    (fov.to_radians() / 2.0).tan() * 3.0 / 4.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::PlayerStartJSON;
+
+    // Run a game on a WorldMap::from_rows map with the real assets in data/ (loaded without SDL), plus extra
+    // entity templates given as JSON. The player starts at start, facing +x
+    fn with_game<F: FnOnce(&mut Game)>(rows: &[&str], start: (f64, f64), extra_templates: &[&str], run: F) {
+        let mut textures = TextureManager::new();
+        textures.load().unwrap();
+        let mut sprites = SpriteManager::new();
+        sprites.load().unwrap();
+        let mut entities = EntityManager::new(&sprites);
+        entities.init().unwrap();
+        for t in extra_templates {
+            entities.add_template(serde_json::from_str(t).unwrap());
+        }
+        let mut animations = AnimationManager::new();
+        animations.init().unwrap();
+        let mut map = WorldMap::from_rows(rows);
+        map.player_starts.push(PlayerStartJSON { x: start.0, y: start.1, dir_x: 1.0, dir_y: 0.0 });
+        let mut game = Game::new(map, &textures, &sprites, &mut entities, &animations).unwrap();
+        run(&mut game);
+    }
+
+    fn spawn(game: &mut Game, name: &str, x: f64, y: f64) -> EntityHandle {
+        return game.spawn_entity(&EntityJSON {
+            name: String::from(name),
+            x: x,
+            y: y,
+            dir_x: -1.0,
+            dir_y: 0.0,
+            animation: String::new(),
+            tags: vec![],
+        });
+    }
+
+    fn entity_pos(game: &Game, handle: EntityHandle) -> Vector2<f64> {
+        let e = &game.entities[handle];
+        return Vector2::new(e.pos.x, e.pos.y);
+    }
+
+    fn room() -> Vec<&'static str> {
+        vec![
+            "########",
+            "#......#",
+            "#......#",
+            "#......#",
+            "########",
+        ]
+    }
+
+    #[test]
+    fn player_pushes_entity() {
+        with_game(&room(), (1.5, 2.5), &[], |game| {
+            let barrel = spawn(game, "barrel", 3.0, 2.5);
+            let pos = game.push_out_of_entities(Vector2::new(2.6, 2.5));
+            let barrel_pos = entity_pos(game, barrel);
+            assert!(barrel_pos.x > 3.0);
+            assert!((barrel_pos - pos).magnitude() >= PLAYER_RADIUS + 0.25 - 1e-9);
+        });
+    }
+
+    #[test]
+    fn player_cant_push_entity_into_another() {
+        with_game(&room(), (1.5, 2.5), &[], |game| {
+            let barrel = spawn(game, "barrel", 3.0, 2.5);
+            let behind = spawn(game, "barrel", 3.5, 2.5);
+            let pos = game.push_out_of_entities(Vector2::new(2.6, 2.5));
+            assert_eq!(entity_pos(game, barrel), Vector2::new(3.0, 2.5));
+            assert_eq!(entity_pos(game, behind), Vector2::new(3.5, 2.5));
+            // The player gives way instead
+            assert!((pos.x - 2.55).abs() < 1e-9);
+        });
+    }
+
+    #[test]
+    fn player_can_push_entity_away_from_one_it_overlaps() {
+        with_game(&room(), (1.5, 2.5), &[], |game| {
+            let barrel = spawn(game, "barrel", 3.0, 2.5);
+            spawn(game, "barrel", 3.2, 2.5);
+            // Pushed towards -x, away from the barrel it's already inside
+            game.push_out_of_entities(Vector2::new(3.4, 2.5));
+            assert!(entity_pos(game, barrel).x < 3.0);
+        });
+    }
+
+    // Shootable scenery with plenty of health and no AI
+    const TARGET: &str = r#"{
+        "name": "target",
+        "sprite_name": "01_barrel",
+        "collidable": true,
+        "collision_radius": 0.25,
+        "health": 1000.0
+    }"#;
+
+    fn corridor() -> Vec<&'static str> {
+        vec![
+            "##########",
+            "#........#",
+            "##########",
+        ]
+    }
+
+    #[test]
+    fn fire_hits_entity_in_the_centre_column() {
+        with_game(&corridor(), (1.5, 1.5), &[TARGET], |game| {
+            let target = spawn(game, "target", 5.5, 1.5);
+            game.fire();
+            assert_eq!(game.entities[target].health.health, 1000.0 - WEAPON_DAMAGE);
+            let events = game.take_damage_events();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].target, DamageTarget::Entity(game.entities[target].id));
+            assert_eq!(events[0].amount, WEAPON_DAMAGE);
+        });
+    }
+
+    #[test]
+    fn fire_hits_nearest_entity() {
+        with_game(&corridor(), (1.5, 1.5), &[TARGET], |game| {
+            let near = spawn(game, "target", 4.5, 1.5);
+            let far = spawn(game, "target", 7.5, 1.5);
+            game.fire();
+            assert_eq!(game.entities[near].health.health, 1000.0 - WEAPON_DAMAGE);
+            assert_eq!(game.entities[far].health.health, 1000.0);
+        });
+    }
+
+    #[test]
+    fn fire_misses_entity_off_centre() {
+        with_game(&room(), (1.5, 1.5), &[TARGET], |game| {
+            let target = spawn(game, "target", 4.5, 3.5);
+            game.fire();
+            assert_eq!(game.entities[target].health.health, 1000.0);
+            assert!(game.take_damage_events().is_empty());
+        });
+    }
+
+    #[test]
+    fn wall_blocks_the_shot() {
+        let rows = [
+            "##########",
+            "#...#....#",
+            "##########",
+        ];
+        with_game(&rows, (1.5, 1.5), &[TARGET], |game| {
+            let target = spawn(game, "target", 6.5, 1.5);
+            game.fire();
+            assert_eq!(game.entities[target].health.health, 1000.0);
+            assert!(game.take_damage_events().is_empty());
+        });
+    }
+
+    #[test]
+    fn fire_ignores_entities_that_cant_be_shot() {
+        with_game(&corridor(), (1.5, 1.5), &[TARGET], |game| {
+            let barrel = spawn(game, "barrel", 3.5, 1.5);
+            let target = spawn(game, "target", 6.5, 1.5);
+            game.fire();
+            assert!(!game.entities[barrel].dead);
+            assert_eq!(game.entities[target].health.health, 1000.0 - WEAPON_DAMAGE);
+            assert_eq!(game.take_damage_events().len(), 1);
+        });
+    }
+
+    #[test]
+    fn holding_fire_waits_for_the_cooldown() {
+        with_game(&corridor(), (1.5, 1.5), &[TARGET], |game| {
+            spawn(game, "target", 5.5, 1.5);
+            let mut input = InputManager::new();
+            input.hold(&[Action::Fire]);
+            let dt = 1.0 / TICK_RATE;
+            game.tick(&input, dt);
+            assert_eq!(game.take_damage_events().len(), 1);
+            // Nothing more until FIRE_DELAY has passed
+            let waiting = (FIRE_DELAY / dt) as u32 - 1;
+            for _ in 0..waiting {
+                game.tick(&input, dt);
+            }
+            assert!(game.take_damage_events().is_empty());
+            for _ in 0..2 {
+                game.tick(&input, dt);
+            }
+            assert_eq!(game.take_damage_events().len(), 1);
+        });
+    }
+
+    #[test]
+    fn damaging_the_player_emits_events() {
+        with_game(&room(), (1.5, 1.5), &[], |game| {
+            game.damage_player(30.0, None);
+            let events = game.take_damage_events();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].target, DamageTarget::Player);
+            assert_eq!(events[0].amount, 30.0);
+            assert!(!events[0].killed);
+            assert_eq!(game.player_health().health, PLAYER_HEALTH - 30.0);
+            assert!(game.take_damage_events().is_empty());
+        });
+    }
+
+    #[test]
+    fn killing_the_player_starts_the_death_camera() {
+        with_game(&room(), (1.5, 1.5), &[], |game| {
+            game.damage_player(PLAYER_HEALTH + 50.0, Some(Vector2::new(4.5, 2.5)));
+            let events = game.take_damage_events();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].amount, PLAYER_HEALTH);
+            assert!(events[0].killed);
+            assert!(game.death.is_some());
+            // Already dead, nothing more to hurt
+            game.damage_player(10.0, None);
+            assert!(game.take_damage_events().is_empty());
+        });
+    }
+
+    #[test]
+    fn killing_an_entity_emits_event() {
+        with_game(&room(), (1.5, 1.5), &[TARGET], |game| {
+            let target = spawn(game, "target", 4.5, 2.5);
+            let animations = game.animation_manager;
+            let event = game.entities[target].damage(999.0, animations);
+            assert!(!event.killed);
+            let event = game.entities[target].damage(999.0, animations);
+            assert_eq!(event.amount, 1.0);
+            assert!(event.killed);
+            assert!(game.entities[target].dead);
+            assert!(!game.entities[target].collidable);
+            let event = game.entities[target].damage(999.0, animations);
+            assert_eq!(event.amount, 0.0);
+            assert!(!event.killed);
+        });
+    }
+
+    // Hitscan enemy that has to be within 3 cells to shoot
+    const GUARD: &str = r#"{
+        "name": "guard",
+        "sprite_name": "04_soldier",
+        "collidable": true,
+        "collision_radius": 0.3,
+        "health": 100.0,
+        "ai": {
+            "sight_range": 8.0, "reaction_time": 0.5, "speed": 1.0,
+            "attack": "hitscan", "attack_range": 3.0, "attack_damage": 10.0, "attack_cooldown": 1.0
+        }
+    }"#;
+
+    fn ai_state(game: &Game, handle: EntityHandle) -> AiState {
+        return game.entities[handle].ai.as_ref().unwrap().state;
+    }
+
+    // Run the AI for a number of seconds in fixed steps
+    fn run_ai(game: &mut Game, seconds: f64) {
+        let dt = 1.0 / TICK_RATE;
+        for _ in 0..(seconds / dt).round() as u32 {
+            game.tick_ai(dt);
+        }
+    }
+
+    #[test]
+    fn idle_enemy_spots_player_then_chases() {
+        with_game(&corridor(), (1.5, 1.5), &[GUARD], |game| {
+            let guard = spawn(game, "guard", 7.5, 1.5);
+            game.entities[guard].dir = Vector2::new(0.0, 1.0);
+            assert_eq!(ai_state(game, guard), AiState::Idle);
+            game.tick_ai(1.0 / TICK_RATE);
+            assert_eq!(ai_state(game, guard), AiState::Alert);
+            // Turned to face the player straight away
+            assert_eq!(game.entities[guard].dir, Vector2::new(-1.0, 0.0));
+            run_ai(game, 0.4);
+            assert_eq!(ai_state(game, guard), AiState::Alert);
+            run_ai(game, 0.2);
+            assert_eq!(ai_state(game, guard), AiState::Chase);
+            // Out of range, so it closes in
+            run_ai(game, 1.0);
+            assert!(entity_pos(game, guard).x < 7.0);
+            assert!(game.take_damage_events().is_empty());
+        });
+    }
+
+    #[test]
+    fn enemy_doesnt_see_through_walls() {
+        let rows = [
+            "##########",
+            "#...#....#",
+            "##########",
+        ];
+        with_game(&rows, (1.5, 1.5), &[GUARD], |game| {
+            let guard = spawn(game, "guard", 7.5, 1.5);
+            run_ai(game, 1.0);
+            assert_eq!(ai_state(game, guard), AiState::Idle);
+        });
+    }
+
+    #[test]
+    fn enemy_doesnt_see_past_sight_range() {
+        let rows = [
+            "############",
+            "#..........#",
+            "############",
+        ];
+        with_game(&rows, (1.5, 1.5), &[GUARD], |game| {
+            let guard = spawn(game, "guard", 10.5, 1.5);
+            run_ai(game, 1.0);
+            assert_eq!(ai_state(game, guard), AiState::Idle);
+        });
+    }
+
+    #[test]
+    fn enemy_attacks_in_range_then_cools_down() {
+        with_game(&corridor(), (1.5, 1.5), &[GUARD], |game| {
+            let guard = spawn(game, "guard", 4.0, 1.5);
+            let animations = game.animation_manager;
+            game.entities[guard].set_ai_state(AiState::Chase, 0.0, animations);
+            game.tick_ai(1.0 / TICK_RATE);
+            assert_eq!(ai_state(game, guard), AiState::Attack);
+            assert_eq!(game.player_health().health, PLAYER_HEALTH - 10.0);
+            // Holds still for the attack, then goes back to chasing
+            run_ai(game, ATTACK_TIME - 0.1);
+            assert_eq!(ai_state(game, guard), AiState::Attack);
+            assert_eq!(entity_pos(game, guard), Vector2::new(4.0, 1.5));
+            run_ai(game, 0.2);
+            assert_eq!(ai_state(game, guard), AiState::Chase);
+            // No second shot until the cooldown is over
+            assert_eq!(game.take_damage_events().len(), 1);
+            run_ai(game, 0.5);
+            assert_eq!(game.take_damage_events().len(), 1);
+        });
+    }
+
+    #[test]
+    fn melee_enemy_has_to_get_close() {
+        let melee = GUARD.replace("hitscan", "melee").replace("\"attack_range\": 3.0", "\"attack_range\": 0.5");
+        with_game(&corridor(), (1.5, 1.5), &[&melee], |game| {
+            let guard = spawn(game, "guard", 4.0, 1.5);
+            let animations = game.animation_manager;
+            game.entities[guard].set_ai_state(AiState::Chase, 0.0, animations);
+            game.tick_ai(1.0 / TICK_RATE);
+            assert_eq!(ai_state(game, guard), AiState::Chase);
+            assert!(game.take_damage_events().is_empty());
+            run_ai(game, 2.0);
+            assert!((entity_pos(game, guard) - Vector2::new(1.5, 1.5)).magnitude() <= 0.5 + 0.3 + PLAYER_RADIUS);
+            assert!(!game.take_damage_events().is_empty());
+        });
+    }
+
+    #[test]
+    fn hurt_enemy_flinches_then_chases() {
+        with_game(&corridor(), (1.5, 1.5), &[GUARD], |game| {
+            let guard = spawn(game, "guard", 7.5, 1.5);
+            let animations = game.animation_manager;
+            game.entities[guard].damage(25.0, animations);
+            assert_eq!(ai_state(game, guard), AiState::Pain);
+            run_ai(game, crate::ai::PAIN_TIME - 0.1);
+            assert_eq!(ai_state(game, guard), AiState::Pain);
+            run_ai(game, 0.2);
+            assert_eq!(ai_state(game, guard), AiState::Chase);
+        });
+    }
+
+    #[test]
+    fn dead_enemy_does_nothing() {
+        with_game(&corridor(), (1.5, 1.5), &[GUARD], |game| {
+            let guard = spawn(game, "guard", 3.0, 1.5);
+            let animations = game.animation_manager;
+            game.entities[guard].damage(100.0, animations);
+            assert_eq!(ai_state(game, guard), AiState::Dead);
+            assert!(game.entities[guard].dead);
+            game.take_damage_events();
+            run_ai(game, 2.0);
+            assert_eq!(ai_state(game, guard), AiState::Dead);
+            assert_eq!(entity_pos(game, guard), Vector2::new(3.0, 1.5));
+            assert!(game.take_damage_events().is_empty());
+        });
+    }
+
+    // Run whole simulation steps with nothing pressed for a number of seconds
+    fn run_ticks(game: &mut Game, seconds: f64) {
+        let input = InputManager::new();
+        let dt = 1.0 / TICK_RATE;
+        for _ in 0..(seconds / dt).round() as u32 {
+            game.tick(&input, dt);
+        }
+    }
+
+    fn exploded(game: &Game, handle: EntityHandle) -> bool {
+        return game.entities[handle].projectile.as_ref().unwrap().exploded;
+    }
+
+    // Step until the projectile bursts, failing if it's still flying after its whole lifetime
+    fn run_until_burst(game: &mut Game, handle: EntityHandle) {
+        let input = InputManager::new();
+        for _ in 0..(5.0 * TICK_RATE) as u32 {
+            if exploded(game, handle) {
+                return;
+            }
+            game.tick(&input, 1.0 / TICK_RATE);
+        }
+        panic!("projectile never burst");
+    }
+
+    #[test]
+    fn only_projectiles_can_be_launched() {
+        with_game(&corridor(), (1.5, 1.5), &[], |game| {
+            assert!(game.spawn_projectile("barrel", Vector2::new(3.0, 1.5), Vector2::new(1.0, 0.0), DamageTarget::Player).is_none());
+            assert!(game.spawn_projectile("fireball", Vector2::new(3.0, 1.5), Vector2::new(0.0, 0.0), DamageTarget::Player).is_none());
+            assert!(game.spawn_projectile("fireball", Vector2::new(3.0, 1.5), Vector2::new(1.0, 0.0), DamageTarget::Player).is_some());
+        });
+    }
+
+    #[test]
+    fn projectile_bursts_on_wall_then_despawns() {
+        with_game(&corridor(), (1.5, 1.5), &[], |game| {
+            let fireball = game.spawn_projectile("fireball", Vector2::new(3.0, 1.5), Vector2::new(1.0, 0.0), DamageTarget::Player).unwrap();
+            run_until_burst(game, fireball);
+            // Stopped just short of the wall, showing its impact
+            let pos = entity_pos(game, fireball);
+            assert!((pos.x - (9.0 - PROJECTILE_WALL_GAP)).abs() < 1e-9);
+            assert!(game.entities[fireball].animation.is_some());
+            assert!(game.take_damage_events().is_empty());
+            run_ticks(game, 1.0);
+            assert!(!game.entities.contains(fireball));
+        });
+    }
+
+    #[test]
+    fn projectile_damages_entity() {
+        with_game(&corridor(), (1.5, 1.5), &[TARGET], |game| {
+            let target = spawn(game, "target", 6.5, 1.5);
+            let fireball = game.spawn_projectile("fireball", Vector2::new(3.0, 1.5), Vector2::new(1.0, 0.0), DamageTarget::Player).unwrap();
+            run_until_burst(game, fireball);
+            assert!(entity_pos(game, fireball).x < 6.5);
+            assert_eq!(game.entities[target].health.health, 980.0);
+            let events = game.take_damage_events();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].target, DamageTarget::Entity(game.entities[target].id));
+        });
+    }
+
+    #[test]
+    fn projectile_damages_player() {
+        with_game(&corridor(), (1.5, 1.5), &[TARGET], |game| {
+            let shooter = spawn(game, "target", 6.5, 1.5);
+            let owner = DamageTarget::Entity(game.entities[shooter].id);
+            // Starts inside its owner, which it flies straight out of
+            let fireball = game.spawn_projectile("fireball", Vector2::new(6.5, 1.5), Vector2::new(-1.0, 0.0), owner).unwrap();
+            run_until_burst(game, fireball);
+            assert_eq!(game.entities[shooter].health.health, 1000.0);
+            assert_eq!(game.player_health().health, PLAYER_HEALTH - 20.0);
+            let events = game.take_damage_events();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].target, DamageTarget::Player);
+        });
+    }
+
+    #[test]
+    fn player_projectile_leaves_the_player_alone() {
+        with_game(&corridor(), (1.5, 1.5), &[], |game| {
+            let fireball = game.spawn_projectile("fireball", Vector2::new(1.5, 1.5), Vector2::new(1.0, 0.0), DamageTarget::Player).unwrap();
+            run_ticks(game, 0.1);
+            assert!(!exploded(game, fireball));
+            assert_eq!(game.player_health().health, PLAYER_HEALTH);
+        });
+    }
+
+    #[test]
+    fn projectile_bursts_when_its_time_runs_out() {
+        with_game(&corridor(), (1.5, 1.5), &[], |game| {
+            let fireball = game.spawn_projectile("fireball", Vector2::new(3.0, 1.5), Vector2::new(1.0, 0.0), DamageTarget::Player).unwrap();
+            game.entities[fireball].projectile.as_mut().unwrap().lifetime = 0.1;
+            run_until_burst(game, fireball);
+            assert!(entity_pos(game, fireball).x < 4.0);
+            run_ticks(game, 1.0);
+            assert!(!game.entities.contains(fireball));
+        });
+    }
+
+    #[test]
+    fn default_fov_gives_original_camera_plane() {
+        assert_eq!(vertical_view(DEFAULT_FOV) * DEFAULT_WIDTH as f64 / DEFAULT_HEIGHT as f64, 0.66);
+    }
+}
@@ -0,0 +1,145 @@
+// Golden image regression tests for the raycaster.
+// Every case renders a map offscreen from a fixed camera pose and compares the frame against a stored
+// reference in tests/golden/. After an intentional rendering change, regenerate the references with:
+//     UPDATE_GOLDEN=1 cargo test golden
+// On a mismatch the actual frame and a diff image are written to target/golden/ for inspection.
+
+use cgmath::Vector2;
+
+use image::GenericImageView;
+
+use std::env;
+use std::fs;
+
+use crate::render_frame;
+use crate::config::Config;
+
+const GOLDEN_DIR: &str = "./tests/golden";
+const OUTPUT_DIR: &str = "./target/golden";
+// Max difference per colour channel before a pixel counts as different
+const CHANNEL_TOLERANCE: i32 = 2;
+// Max fraction of different pixels before the frame counts as a mismatch
+const MAX_DIFF_RATIO: f64 = 0.001;
+
+fn check_golden(name: &str, map_name: &str, pos: (f64, f64), dir: (f64, f64)) {
+    let pose = (Vector2::new(pos.0, pos.1), Vector2::new(dir.0, dir.1));
+    let frame = render_frame(map_name, Some(pose), &Config::default()).unwrap();
+    let golden_path = format!("{}/{}.png", GOLDEN_DIR, name);
+
+    if env::var("UPDATE_GOLDEN").is_ok() {
+        fs::create_dir_all(GOLDEN_DIR).unwrap();
+        frame.save_png(&golden_path).unwrap();
+        return;
+    }
+
+    let golden = match image::open(&golden_path) {
+        Ok(img) => img,
+        Err(e) => panic!("could not open reference {} ({}). Run with UPDATE_GOLDEN=1 to create it", golden_path, e),
+    };
+    assert_eq!(
+        golden.dimensions(), (frame.width, frame.height),
+        "{}: reference size differs from rendered frame", name,
+    );
+    let expected = golden.to_rgba().into_vec();
+    let actual = frame.pixels();
+
+    // Mark every pixel outside tolerance in red on a black diff image
+    let mut diff = vec![0; actual.len()];
+    let mut num_diff = 0;
+    for (i, (a, e)) in actual.chunks(4).zip(expected.chunks(4)).enumerate() {
+        let differs = a.iter().zip(e.iter()).any(|(a, e)| (*a as i32 - *e as i32).abs() > CHANNEL_TOLERANCE);
+        diff[i * 4 + 3] = 255;
+        if differs {
+            num_diff += 1;
+            diff[i * 4] = 255;
+        }
+    }
+
+    let diff_ratio = num_diff as f64 / (frame.width * frame.height) as f64;
+    if diff_ratio > MAX_DIFF_RATIO {
+        fs::create_dir_all(OUTPUT_DIR).unwrap();
+        frame.save_png(&format!("{}/{}.actual.png", OUTPUT_DIR, name)).unwrap();
+        image::save_buffer(
+            format!("{}/{}.diff.png", OUTPUT_DIR, name),
+            &diff, frame.width, frame.height, image::ColorType::Rgba8,
+        ).unwrap();
+        panic!(
+            "{}: {} pixels ({:.3}%) differ from {}. See {}/{}.actual.png and .diff.png",
+            name, num_diff, diff_ratio * 100.0, golden_path, OUTPUT_DIR, name,
+        );
+    }
+}
+
+#[test]
+fn golden_small_start() {
+    // Sprites in front of the far wall, rotating soldier facing sideways
+    check_golden("small_start", "test_map_small", (6.5, 3.5), (-1.0, 0.0));
+}
+
+#[test]
+fn golden_small_close_wall() {
+    // Wall taller than the screen, exercises tex_strip_start trimming
+    check_golden("small_close_wall", "test_map_small", (1.3, 4.5), (-1.0, 0.0));
+}
+
+#[test]
+fn golden_small_pillars() {
+    // Looking down the pillar room through the gap in the middle wall
+    check_golden("small_pillars", "test_map_small", (3.9, 2.5), (0.1, 1.0));
+}
+
+#[test]
+fn golden_small_pickups() {
+    // Key and treasure past the pillars, small and standing on the floor
+    check_golden("small_pickups", "test_map_small", (4.0, 7.5), (0.0, 1.0));
+}
+
+#[test]
+fn golden_large_diagonal() {
+    // Diagonal ray across both wall sides (tex_x flipping on x and y sides)
+    check_golden("large_diagonal", "test_map_large", (10.5, 12.5), (-0.7, -0.7));
+}
+
+#[test]
+fn golden_large_barrels() {
+    check_golden("large_barrels", "test_map_large", (8.5, 6.5), (-1.0, 0.05));
+}
+
+#[test]
+fn golden_textured_corridor() {
+    check_golden("textured_corridor", "map_textured", (3.5, 13.5), (1.0, 0.0));
+}
+
+#[test]
+fn golden_textured_room() {
+    check_golden("textured_room", "map_textured", (12.5, 8.5), (0.3, -1.0));
+}
+
+#[test]
+fn golden_textured_door() {
+    // Closed door recessed into its cell at the end of the corridor
+    check_golden("textured_door", "map_textured", (12.5, 13.5), (0.0, -1.0));
+}
+
+#[test]
+fn golden_textured_door_angled() {
+    // Door slab seen at an angle, with the side walls of the doorway visible
+    check_golden("textured_door_angled", "map_textured", (4.2, 7.8), (0.35, -1.0));
+}
+
+#[test]
+fn golden_open_gap() {
+    // Rays escaping through a gap in the outer wall, border floor repeating out to the view distance
+    check_golden("open_gap", "test_map_open", (4.5, 4.5), (0.2, -1.0));
+}
+
+#[test]
+fn golden_open_side() {
+    check_golden("open_side", "test_map_open", (3.5, 3.5), (-1.0, 0.1));
+}
+
+#[test]
+fn golden_map_pillars() {
+    // Across the open hall to the pillar grid and the room walls behind it
+    check_golden("map_pillars", "map", (22.5, 12.5), (-1.0, -0.3));
+}
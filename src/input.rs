@@ -0,0 +1,377 @@
+extern crate sdl2;
+
+use sdl2::controller::Axis;
+use sdl2::controller::Button;
+use sdl2::controller::GameController;
+use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::error::Error;
+
+const BINDINGS_PATH: &str = "./data/bindings.json";
+// Analog value an action needs to count as pressed (e.g. a trigger pulled halfway)
+const PRESS_THRESHOLD: f64 = 0.5;
+
+// Everything the player can do, independent of which key/button does it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Forward,
+    Back,
+    StrafeLeft,
+    StrafeRight,
+    TurnLeft,
+    TurnRight,
+    Use,
+    Fire,
+    Run,
+    DebugKill,
+    DebugRevive,
+    DebugHurt,
+}
+
+impl Action {
+    // Name used in the bindings file
+    pub fn from_name(name: &str) -> Option<Action> {
+        match name {
+            "forward" => Some(Action::Forward),
+            "back" => Some(Action::Back),
+            "strafe_left" => Some(Action::StrafeLeft),
+            "strafe_right" => Some(Action::StrafeRight),
+            "turn_left" => Some(Action::TurnLeft),
+            "turn_right" => Some(Action::TurnRight),
+            "use" => Some(Action::Use),
+            "fire" => Some(Action::Fire),
+            "run" => Some(Action::Run),
+            "debug_kill" => Some(Action::DebugKill),
+            "debug_revive" => Some(Action::DebugRevive),
+            "debug_hurt" => Some(Action::DebugHurt),
+            _ => None,
+        }
+    }
+}
+
+// One physical input that can trigger an action
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Binding {
+    Key(Keycode),
+    Mouse(MouseButton),
+    Pad(Button),
+    // Stick or trigger, only counting movement in one direction (+1.0 or -1.0)
+    Axis(Axis, f64),
+}
+
+impl Binding {
+    // "key:<SDL key name>", "mouse:left|middle|right", "pad:<SDL controller button name>"
+    // or "axis:<SDL controller axis name><+ or ->"
+    pub fn parse(s: &str) -> Option<Binding> {
+        let mut parts = s.splitn(2, ':');
+        let device = parts.next()?;
+        let name = parts.next()?;
+        match device {
+            "key" => Keycode::from_name(name).map(Binding::Key),
+            "mouse" => match name {
+                "left" => Some(Binding::Mouse(MouseButton::Left)),
+                "middle" => Some(Binding::Mouse(MouseButton::Middle)),
+                "right" => Some(Binding::Mouse(MouseButton::Right)),
+                _ => None,
+            },
+            "pad" => pad_button(name).map(Binding::Pad),
+            "axis" => {
+                let sign = match name.chars().last()? {
+                    '+' => 1.0,
+                    '-' => -1.0,
+                    _ => return None,
+                };
+                pad_axis(&name[..name.len() - 1]).map(|a| Binding::Axis(a, sign))
+            },
+            _ => None,
+        }
+    }
+}
+
+// Same names as SDL's controller mapping strings
+fn pad_button(name: &str) -> Option<Button> {
+    match name {
+        "a" => Some(Button::A),
+        "b" => Some(Button::B),
+        "x" => Some(Button::X),
+        "y" => Some(Button::Y),
+        "back" => Some(Button::Back),
+        "guide" => Some(Button::Guide),
+        "start" => Some(Button::Start),
+        "leftstick" => Some(Button::LeftStick),
+        "rightstick" => Some(Button::RightStick),
+        "leftshoulder" => Some(Button::LeftShoulder),
+        "rightshoulder" => Some(Button::RightShoulder),
+        "dpup" => Some(Button::DPadUp),
+        "dpdown" => Some(Button::DPadDown),
+        "dpleft" => Some(Button::DPadLeft),
+        "dpright" => Some(Button::DPadRight),
+        _ => None,
+    }
+}
+
+fn pad_axis(name: &str) -> Option<Axis> {
+    match name {
+        "leftx" => Some(Axis::LeftX),
+        "lefty" => Some(Axis::LeftY),
+        "rightx" => Some(Axis::RightX),
+        "righty" => Some(Axis::RightY),
+        "lefttrigger" => Some(Axis::TriggerLeft),
+        "righttrigger" => Some(Axis::TriggerRight),
+        _ => None,
+    }
+}
+
+// Turns keyboard, mouse and gamepad state into actions once per frame
+pub struct InputManager {
+    bindings: HashMap<Action, Vec<Binding>>,
+    controllers: HashMap<u32, GameController>, // By joystick instance id
+    dead_zone: f64, // Fraction of stick travel ignored around the centre
+    curve: f64, // Response curve exponent, 1.0 is linear
+    values: HashMap<Action, f64>, // 0.0 -> 1.0, keys and buttons are always 1.0
+    down: HashSet<Action>,
+    pressed_since_tick: HashSet<Action>, // Went down at some update since the last simulation step
+    pub mouse_dx: i32, // Horizontal mouse motion not yet used by a simulation step, 0 when the mouse isn't captured
+}
+
+impl InputManager {
+    pub fn new() -> InputManager {
+        InputManager {
+            bindings: HashMap::new(),
+            controllers: HashMap::new(),
+            dead_zone: 0.0,
+            curve: 1.0,
+            values: HashMap::new(),
+            down: HashSet::new(),
+            pressed_since_tick: HashSet::new(),
+            mouse_dx: 0,
+        }
+    }
+
+    // Load data/bindings.json: action name -> list of inputs
+    pub fn init(&mut self) -> Result<&Self, Box<dyn Error>> {
+        let mut file = File::open(BINDINGS_PATH)?;
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
+        let json: HashMap<String, Vec<String>> = serde_json::from_str(&data)?;
+
+        let mut bindings = HashMap::new();
+        for (name, inputs) in json {
+            let action = match Action::from_name(&name) {
+                Some(a) => a,
+                None => return Err(format!("{}: unknown action {}", BINDINGS_PATH, name).into()),
+            };
+            let mut parsed = vec![];
+            for input in inputs {
+                match Binding::parse(&input) {
+                    Some(b) => parsed.push(b),
+                    None => return Err(format!("{}: {}: unknown input {}", BINDINGS_PATH, name, input).into()),
+                }
+            }
+            bindings.insert(action, parsed);
+        }
+        self.bindings = bindings;
+
+        return Ok(self);
+    }
+
+    pub fn set_pad_response(&mut self, dead_zone: f64, curve: f64) {
+        self.dead_zone = dead_zone;
+        self.curve = curve;
+    }
+
+    // Controllers are opened/closed by the main loop as they're plugged in and out
+    pub fn add_controller(&mut self, controller: GameController) {
+        self.controllers.insert(controller.instance_id() as u32, controller);
+    }
+
+    pub fn remove_controller(&mut self, instance_id: u32) {
+        self.controllers.remove(&instance_id);
+    }
+
+    // Stick position in the bound direction after the dead zone and response curve, 0.0 -> 1.0
+    fn axis_value(&self, axis: Axis, sign: f64) -> f64 {
+        let mut value: f64 = 0.0;
+        for controller in self.controllers.values() {
+            let raw = (controller.axis(axis) as f64 / i16::MAX as f64 * sign).min(1.0);
+            value = value.max(raw);
+        }
+        return pad_response(value, self.dead_zone, self.curve);
+    }
+
+    // Work out how far each action is held this frame. Mouse motion adds up until a simulation step uses it
+    pub fn update(&mut self, event_pump: &sdl2::EventPump, mouse_dx: i32) {
+        let keys: HashSet<Keycode> = event_pump
+            .keyboard_state()
+            .pressed_scancodes()
+            .filter_map(Keycode::from_scancode)
+            .collect();
+        let mouse = event_pump.mouse_state();
+
+        let pressed = |held: bool| if held { 1.0 } else { 0.0 };
+
+        let mut values = HashMap::new();
+        for (action, bindings) in self.bindings.iter() {
+            // Strongest of all the inputs bound to it
+            let value = bindings.iter().map(|b| match b {
+                Binding::Key(k) => pressed(keys.contains(k)),
+                Binding::Mouse(m) => pressed(mouse.is_mouse_button_pressed(*m)),
+                Binding::Pad(p) => pressed(self.controllers.values().any(|c| c.button(*p))),
+                Binding::Axis(a, sign) => self.axis_value(*a, *sign),
+            }).fold(0.0, f64::max);
+            values.insert(*action, value);
+        }
+
+        self.set_values(values);
+        self.mouse_dx += mouse_dx;
+    }
+
+    // Remember which actions went down, even if they're released again before the next simulation step
+    fn set_values(&mut self, values: HashMap<Action, f64>) {
+        let down: HashSet<Action> = values.iter().filter(|(_, v)| **v >= PRESS_THRESHOLD).map(|(a, _)| *a).collect();
+        self.pressed_since_tick.extend(down.difference(&self.down));
+        self.down = down;
+        self.values = values;
+    }
+
+    // Call after each simulation step, so presses and mouse motion only count once
+    pub fn end_tick(&mut self) {
+        self.pressed_since_tick.clear();
+        self.mouse_dx = 0;
+    }
+
+    // How far the action is held, for movement. 0.0 -> 1.0
+    pub fn value(&self, action: Action) -> f64 {
+        *self.values.get(&action).unwrap_or(&0.0)
+    }
+
+    pub fn pressed(&self, action: Action) -> bool {
+        self.down.contains(&action)
+    }
+
+    // Pressed since the last simulation step, whether or not it's still held
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.pressed_since_tick.contains(&action)
+    }
+}
+
+// Stick travel (0.0 -> 1.0) with the dead zone taken out and the rest rescaled to 0.0 -> 1.0, then curved
+fn pad_response(value: f64, dead_zone: f64, curve: f64) -> f64 {
+    if value <= dead_zone {
+        return 0.0;
+    }
+    return ((value - dead_zone) / (1.0 - dead_zone)).powf(curve);
+}
+
+// Held actions for tests, as if they'd come from bound inputs
+#[cfg(test)]
+impl InputManager {
+    pub fn hold(&mut self, held: &[Action]) {
+        self.set_values(held.iter().map(|a| (*a, 1.0)).collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_bindings() {
+        assert_eq!(Binding::parse("key:W"), Some(Binding::Key(Keycode::W)));
+        assert_eq!(Binding::parse("key:Left Shift"), Some(Binding::Key(Keycode::LShift)));
+        assert_eq!(Binding::parse("mouse:left"), Some(Binding::Mouse(MouseButton::Left)));
+        assert_eq!(Binding::parse("mouse:right"), Some(Binding::Mouse(MouseButton::Right)));
+        assert_eq!(Binding::parse("pad:a"), Some(Binding::Pad(Button::A)));
+        assert_eq!(Binding::parse("pad:dpleft"), Some(Binding::Pad(Button::DPadLeft)));
+        assert_eq!(Binding::parse("axis:leftx-"), Some(Binding::Axis(Axis::LeftX, -1.0)));
+        assert_eq!(Binding::parse("axis:righttrigger+"), Some(Binding::Axis(Axis::TriggerRight, 1.0)));
+    }
+
+    #[test]
+    fn parse_bad_bindings() {
+        for s in ["", "W", "key:", "key:NotAKey", "mouse:back", "pad:z", "axis:leftx", "axis:leftx*", "axis:+", "axis:", "joy:a", ":W"].iter() {
+            assert_eq!(Binding::parse(s), None, "{} parsed", s);
+        }
+    }
+
+    #[test]
+    fn shipped_bindings_load() {
+        let mut input = InputManager::new();
+        assert!(input.init().is_ok());
+        assert!(!input.bindings.is_empty());
+    }
+
+    #[test]
+    fn dead_zone_and_curve() {
+        assert_eq!(pad_response(0.1, 0.2, 1.0), 0.0);
+        assert_eq!(pad_response(0.2, 0.2, 1.0), 0.0);
+        assert!((pad_response(0.6, 0.2, 1.0) - 0.5).abs() < 1e-12);
+        assert!((pad_response(0.6, 0.2, 2.0) - 0.25).abs() < 1e-12);
+        assert_eq!(pad_response(0.5, 0.0, 1.0), 0.5);
+    }
+
+    #[test]
+    fn dead_zone_is_rescaled_to_full_range() {
+        // Just past the dead zone starts from nothing, full travel still reaches 1.0
+        for dead_zone in [0.0, 0.2, 0.5, 0.9].iter() {
+            assert!(pad_response(dead_zone + 1e-9, *dead_zone, 1.0) < 1e-6);
+            assert_eq!(pad_response(1.0, *dead_zone, 1.0), 1.0);
+            let half = dead_zone + (1.0 - dead_zone) / 2.0;
+            assert!((pad_response(half, *dead_zone, 1.0) - 0.5).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn curve_keeps_the_endpoints() {
+        for curve in [0.5, 1.0, 2.0, 3.0].iter() {
+            assert_eq!(pad_response(0.0, 0.0, *curve), 0.0);
+            assert_eq!(pad_response(0.2, 0.2, *curve), 0.0);
+            assert_eq!(pad_response(1.0, 0.0, *curve), 1.0);
+            assert_eq!(pad_response(1.0, 0.2, *curve), 1.0);
+        }
+        // Above 1 it's finer near the centre, below 1 coarser
+        assert!(pad_response(0.5, 0.0, 2.0) < 0.5);
+        assert!(pad_response(0.5, 0.0, 0.5) > 0.5);
+    }
+
+    #[test]
+    fn press_counts_until_the_next_tick() {
+        let mut input = InputManager::new();
+        input.hold(&[Action::Use]);
+        assert!(input.just_pressed(Action::Use));
+        // Still held at the next update, still the same press
+        input.hold(&[Action::Use]);
+        assert!(input.just_pressed(Action::Use));
+        input.end_tick();
+        input.hold(&[Action::Use]);
+        assert!(input.pressed(Action::Use));
+        assert!(!input.just_pressed(Action::Use));
+    }
+
+    #[test]
+    fn press_released_before_the_tick_still_counts() {
+        let mut input = InputManager::new();
+        input.hold(&[Action::Use]);
+        input.hold(&[]);
+        assert!(!input.pressed(Action::Use));
+        assert!(input.just_pressed(Action::Use));
+        input.end_tick();
+        assert!(!input.just_pressed(Action::Use));
+    }
+
+    #[test]
+    fn analog_press_needs_the_threshold() {
+        let mut input = InputManager::new();
+        let mut half = HashMap::new();
+        half.insert(Action::Fire, PRESS_THRESHOLD * 0.5);
+        input.set_values(half);
+        assert!(!input.pressed(Action::Fire));
+        assert!(!input.just_pressed(Action::Fire));
+        assert_eq!(input.value(Action::Fire), PRESS_THRESHOLD * 0.5);
+    }
+}
@@ -7,54 +7,121 @@ mod sprites;
 mod textures;
 mod game;
 mod animation;
+mod render;
+mod validation;
+mod config;
+mod input;
+mod damage;
+mod ai;
+mod pathfinding;
+mod projectile;
+mod entity_store;
+mod pickup;
+#[cfg(test)]
+mod golden_tests;
 
 use crate::game::Game;
+use crate::game::TICK_RATE;
 use crate::data::WorldMap;
+use crate::render::FrameBuffer;
+use crate::render::SdlTarget;
+use crate::validation::MapAssets;
+use crate::config::Config;
+use crate::config::DEFAULT_HEIGHT;
+use crate::input::InputManager;
+use crate::damage::DamageTarget;
+use crate::damage::Health;
+use crate::pickup::Inventory;
 
 use sdl2::event::Event;
+use sdl2::event::WindowEvent;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
 use sdl2::render::Texture;
+use sdl2::render::BlendMode;
 use sdl2::video::WindowContext;
 
+use cgmath::Vector2;
+
 use std::collections::HashMap;
+use std::error::Error;
 use std::str;
 use std::env;
+use std::process;
 
-const SCREEN_WIDTH: i32 = 800;
-const SCREEN_HEIGHT: i32 = 600;
+const MAX_FRAME_TIME: f64 = 0.25; // Seconds
+const HURT_FLASH_TIME: f64 = 0.3; // Seconds the screen flashes red after the player is hit
+const MESSAGE_TIME: f64 = 2.0; // Seconds a HUD message stays up
+const ENEMY_TAG: &str = "enemy"; // Entities with this tag count towards kills
+const FONT_SIZE: u16 = 35; // Glyphs are drawn at this height on a DEFAULT_HEIGHT screen
 
 pub fn main() {
     // Get map name
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // Resolution from the config file, command line options win
+    let mut config = match Config::load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Could not load config: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = config.apply_args(&mut args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    // Validation mode: --check-map <map>
+    if args.len() > 2 && args[1] == "--check-map" {
+        match check_map(&args[2]) {
+            Ok(()) => println!("map {}: ok", args[2]),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
     let mut map_name = "test_map_small";
     if args.len() > 1 {
         map_name = &args[1];
     }
-    // Init map
-    let world_map = WorldMap::load_map(map_name).unwrap();
-
+    // Headless mode: <map> --render <out.png> [x y dir_x dir_y]
+    if args.len() > 3 && args[2] == "--render" {
+        let pose = match parse_pose(&args[4..]) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        if let Err(e) = render_headless(map_name, &args[3], pose, &config) {
+            eprintln!("Could not render map {}: {}", map_name, e);
+            process::exit(1);
+        }
+        return;
+    }
     // SDL setup and loop
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
     let window = video_subsystem
-        .window("rust-sdl2 demo", SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+        .window("rust-sdl2 demo", config.width, config.height)
         .position_centered()
         .resizable()
         .build()
         .unwrap();
 
     let mut canvas = window.into_canvas().build().unwrap();
-    canvas.set_logical_size(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32).unwrap();
+    let (render_width, render_height) = config.render_size(config.width, config.height);
+    canvas.set_logical_size(render_width, render_height).unwrap();
     // Load textures
     // Wall/Floor textures
     let creator = canvas.texture_creator();
     let mut texture_manager = textures::TextureManager::new();
     texture_manager.init(&creator).unwrap();
-    let mut floor_texture = creator.create_texture_streaming(PixelFormatEnum::RGBA32, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32).unwrap();
+    let mut floor_texture = creator.create_texture_streaming(PixelFormatEnum::RGBA32, render_width, render_height).unwrap();
 
     // Load sprites
     let mut sprite_manager = sprites::SpriteManager::new();
@@ -68,36 +135,136 @@ pub fn main() {
     let mut animation_manager = animation::AnimationManager::new();
     animation_manager.init().unwrap();
 
+    // Load control bindings
+    let mut input = InputManager::new();
+    if let Err(e) = input.init() {
+        eprintln!("Could not load bindings: {}", e);
+        process::exit(1);
+    }
+    input.set_pad_response(config.pad_dead_zone, config.pad_curve);
+    // Controllers already plugged in show up as added events on the first frame
+    let controller_subsystem = sdl_context.game_controller().unwrap();
+
+    // Init map, checking everything it references exists before the game starts
+    let assets = MapAssets {
+        textures: &texture_manager,
+        entities: &entity_manager,
+        animations: &animation_manager,
+    };
+    let world_map = match WorldMap::load_map(map_name, &assets) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("Could not load map {}: {}", map_name, e);
+            process::exit(1);
+        }
+    };
+
     // Init game
-    let mut game = Game::new(world_map, &texture_manager, &sprite_manager, &mut entity_manager, &animation_manager, &mut floor_texture);
+    let mut game = match Game::new(world_map, &texture_manager, &sprite_manager, &mut entity_manager, &animation_manager) {
+        Ok(g) => g,
+        Err(e) => {
+            eprintln!("Could not start map {}: {}", map_name, e);
+            process::exit(1);
+        }
+    };
+    game.set_resolution(render_width, render_height);
+    game.set_fov(config.fov);
+    game.set_mouse_look(config.mouse_sensitivity, config.invert_mouse);
 
     // Font textures
     let font_textures = generate_font_textures(&creator);
 
     canvas.clear();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    // Time counter for last frame
-    let mut old_time: u32 = 0;
+    // Time counter for last frame, in performance counter units (much finer than ms ticks)
+    let timer = sdl_context.timer().unwrap();
+    let mut old_time = timer.performance_counter();
+    // Real time not yet simulated
+    let mut accumulator = 0.0;
     let mut frames = 0;
     let mut fps = 0.0;
+    // Mouse is only captured while mouse look is on, the window has focus and the game isn't paused
+    let mouse = sdl_context.mouse();
+    let mut mouse_captured = false;
+    let mut focused = true;
+    let mut paused = false;
+    let mut hurt_flash = 0.0;
+    let mut kills = 0;
+    let mut message = String::new();
+    let mut message_timer = 0.0;
     // Buffer of wall distance for each x-stripe. Used later for sprite occlusion
     'running: loop {
         // Clear screen
         canvas.set_draw_color(Color::RGB(128, 128, 128));
         canvas.clear();
         // Get frame time
-        let time = sdl_context.timer().unwrap().ticks();
-        let frame_time = (time - old_time) as f64 / 1000.0; // in seconds
+        let time = timer.performance_counter();
+        let frame_time = (time - old_time) as f64 / timer.performance_frequency() as f64; // in seconds
         old_time = time;
-        // Render Game frame
-        game.draw(&mut canvas, frame_time);
+        // Turn keyboard/mouse/gamepad state into actions, then run as many fixed simulation steps as real time allows.
+        // The world is frozen while paused
+        if !paused {
+            let mouse_dx = match mouse_captured {
+                true => event_pump.relative_mouse_state().x(),
+                false => 0,
+            };
+            input.update(&event_pump, mouse_dx);
+            // Don't try to catch up on long stalls (e.g. dragging the window)
+            accumulator += frame_time.min(MAX_FRAME_TIME);
+            while accumulator >= 1.0 / TICK_RATE {
+                game.tick(&input, 1.0 / TICK_RATE);
+                input.end_tick();
+                accumulator -= 1.0 / TICK_RATE;
+            }
+        }
+        // Death camera is done, back to the start of the level
+        if game.wants_restart() {
+            if let Err(e) = game.restart(map_name) {
+                eprintln!("Could not restart map {}: {}", map_name, e);
+                process::exit(1);
+            }
+            accumulator = 0.0;
+            kills = 0;
+            message_timer = 0.0;
+        }
+        // Render Game frame, between the last two simulation steps
+        game.draw(&mut SdlTarget::new(&mut canvas, &mut floor_texture), accumulator * TICK_RATE);
+        // HUD reacts to damage
+        for event in game.take_damage_events() {
+            match event.target {
+                DamageTarget::Player if event.amount > 0.0 => hurt_flash = HURT_FLASH_TIME,
+                DamageTarget::Entity(id) if event.killed && is_enemy(&game, id) => kills += 1,
+                _ => {},
+            }
+        }
+        // Newest message replaces whatever's showing
+        if let Some(m) = game.take_messages().pop() {
+            message = m;
+            message_timer = MESSAGE_TIME;
+        }
+        // There's no audio output yet, drop sound events so they don't pile up
+        game.take_sound_events();
+        hurt_flash = (hurt_flash - frame_time).max(0.0);
+        message_timer = (message_timer - frame_time).max(0.0);
+        let red = (hurt_flash / HURT_FLASH_TIME * 0.4).max(game.death_progress() * 0.7);
+        draw_damage_overlay(&mut canvas, game.resolution(), red);
         // Draw FPS counter
         if frames % 30 == 0 {
             fps = get_fps(frame_time);
         }
-        draw_fps(&mut canvas, fps, &font_textures);
-        // Read keyboard state and move the player/camera accordingly
-        game.move_player(&event_pump, frame_time);
+        draw_fps(&mut canvas, fps, game.resolution(), &font_textures);
+        draw_secrets(&mut canvas, game.secrets(), game.resolution(), &font_textures);
+        let enemies = game.entities().find_by_tag(ENEMY_TAG).count();
+        render_string(&format!("kills: {}/{}", kills, enemies), hud_line(game.resolution(), 2), &mut canvas, &font_textures);
+        draw_crosshair(&mut canvas, game.resolution());
+        draw_health(&mut canvas, game.player_health(), game.resolution(), &font_textures);
+        draw_inventory(&mut canvas, game.inventory(), game.resolution(), &font_textures);
+        if message_timer > 0.0 {
+            render_string(&message, hud_line(game.resolution(), 4), &mut canvas, &font_textures);
+        }
+        if paused {
+            render_string("paused", hud_line(game.resolution(), 3), &mut canvas, &font_textures);
+        }
 
         for event in event_pump.poll_iter() {
             match event {
@@ -106,9 +273,54 @@ pub fn main() {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => break 'running,
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    repeat: false,
+                    ..
+                } => paused = !paused,
+                // Gamepad hot-plug. Added gives a device index, removed an instance id
+                Event::ControllerDeviceAdded { which, .. } => {
+                    match controller_subsystem.open(which) {
+                        Ok(c) => input.add_controller(c),
+                        Err(e) => eprintln!("Could not open controller {}: {}", which, e),
+                    }
+                },
+                Event::ControllerDeviceRemoved { which, .. } => input.remove_controller(which),
+                Event::Window {
+                    win_event: WindowEvent::FocusGained,
+                    ..
+                } => focused = true,
+                Event::Window {
+                    win_event: WindowEvent::FocusLost,
+                    ..
+                } => focused = false,
+                // Follow the window size, reallocating everything sized to the render resolution
+                Event::Window {
+                    win_event: WindowEvent::Resized(width, height),
+                    ..
+                }
+                | Event::Window {
+                    win_event: WindowEvent::SizeChanged(width, height),
+                    ..
+                } => {
+                    let (render_width, render_height) = config.render_size(width as u32, height as u32);
+                    if (render_width, render_height) != game.resolution() {
+                        game.set_resolution(render_width, render_height);
+                        floor_texture = creator.create_texture_streaming(PixelFormatEnum::RGBA32, render_width, render_height).unwrap();
+                        // SDL centers the logical size in the window, giving the letterbox bars
+                        canvas.set_logical_size(render_width, render_height).unwrap();
+                    }
+                },
                 _ => {}
             }
         }
+        let capture = config.mouse_look && focused && !paused;
+        if capture != mouse_captured {
+            mouse.set_relative_mouse_mode(capture);
+            // Throw away any motion from while the mouse was free
+            event_pump.relative_mouse_state();
+            mouse_captured = capture;
+        }
 
         canvas.present();
         // ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
@@ -116,8 +328,147 @@ pub fn main() {
     }
 }
 
-pub fn draw_fps(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, fps: f64, font_textures: &HashMap<char, Texture>) {
-    render_string(&format!("fps: {0:.1}", fps), Rect::new(30, 30, 20, 35), canvas, font_textures);
+// Render a single frame of a map to a PNG without opening a window
+pub fn render_headless(map_name: &str, out_path: &str, pose: Option<(Vector2<f64>, Vector2<f64>)>, config: &Config) -> Result<(), Box<dyn Error>> {
+    let frame = render_frame(map_name, pose, config)?;
+    frame.save_png(out_path)?;
+
+    return Ok(());
+}
+
+// Camera pose from the --render arguments: nothing for the player start, or x y dir_x dir_y
+fn parse_pose(args: &[String]) -> Result<Option<(Vector2<f64>, Vector2<f64>)>, Box<dyn Error>> {
+    let mut pose = vec![];
+    for a in args {
+        match a.parse::<f64>() {
+            Ok(v) => pose.push(v),
+            Err(_) => return Err(format!("--render pose value '{}' is not a number", a).into()),
+        }
+    }
+    return match pose.len() {
+        0 => Ok(None),
+        4 => Ok(Some((Vector2::new(pose[0], pose[1]), Vector2::new(pose[2], pose[3])))),
+        _ => Err("--render pose needs exactly 4 numbers: x y dir_x dir_y".into()),
+    };
+}
+
+// Render a single frame of a map into memory
+// pose: camera position and direction, defaults to the normal player start
+// config: render resolution and fov
+pub fn render_frame(map_name: &str, pose: Option<(Vector2<f64>, Vector2<f64>)>, config: &Config) -> Result<FrameBuffer, Box<dyn Error>> {
+    let (texture_manager, sprite_manager, animation_manager) = load_headless_assets()?;
+    let mut entity_manager = sprites::EntityManager::new(&sprite_manager);
+    entity_manager.init()?;
+    let assets = MapAssets {
+        textures: &texture_manager,
+        entities: &entity_manager,
+        animations: &animation_manager,
+    };
+    let world_map = WorldMap::load_map(map_name, &assets)?;
+
+    let mut game = Game::new(world_map, &texture_manager, &sprite_manager, &mut entity_manager, &animation_manager)?;
+    if let Some((pos, dir)) = pose {
+        game.set_player_pose(pos, dir);
+    }
+    game.set_resolution(config.width, config.height);
+    game.set_fov(config.fov);
+    let mut frame = FrameBuffer::new(config.width, config.height);
+    game.draw(&mut frame, 1.0);
+
+    return Ok(frame);
+}
+
+// Load a map and check it against all the assets, without starting the game
+pub fn check_map(map_name: &str) -> Result<(), Box<dyn Error>> {
+    let (texture_manager, sprite_manager, animation_manager) = load_headless_assets()?;
+    let mut entity_manager = sprites::EntityManager::new(&sprite_manager);
+    entity_manager.init()?;
+    let assets = MapAssets {
+        textures: &texture_manager,
+        entities: &entity_manager,
+        animations: &animation_manager,
+    };
+    WorldMap::load_map(map_name, &assets)?;
+
+    return Ok(());
+}
+
+// Assets loaded as raw data only, no SDL video needed
+fn load_headless_assets() -> Result<(textures::TextureManager<'static>, sprites::SpriteManager<'static>, animation::AnimationManager), Box<dyn Error>> {
+    let mut texture_manager = textures::TextureManager::new();
+    texture_manager.load()?;
+    let mut sprite_manager = sprites::SpriteManager::new();
+    sprite_manager.load()?;
+    let mut animation_manager = animation::AnimationManager::new();
+    animation_manager.init()?;
+
+    return Ok((texture_manager, sprite_manager, animation_manager));
+}
+
+// Where a line of HUD text goes. Lines count down from the top, or up from the bottom if negative.
+// Laid out for a DEFAULT_HEIGHT screen and scaled to the current one, so nothing overlaps at low resolutions
+fn hud_line(resolution: (u32, u32), line: i32) -> Rect {
+    let scale = resolution.1 as f64 / DEFAULT_HEIGHT as f64;
+    let y = match line >= 0 {
+        true => 30.0 + 40.0 * line as f64,
+        false => DEFAULT_HEIGHT as f64 - 25.0 + 40.0 * line as f64,
+    };
+    return Rect::new((30.0 * scale) as i32, (y * scale) as i32, (20.0 * scale) as u32, (FONT_SIZE as f64 * scale) as u32);
+}
+
+pub fn draw_fps(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, fps: f64, resolution: (u32, u32), font_textures: &HashMap<char, Texture>) {
+    render_string(&format!("fps: {0:.1}", fps), hud_line(resolution, 0), canvas, font_textures);
+}
+
+pub fn draw_secrets(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, secrets: (u32, u32), resolution: (u32, u32), font_textures: &HashMap<char, Texture>) {
+    render_string(&format!("secrets: {} of {}", secrets.0, secrets.1), hud_line(resolution, 1), canvas, font_textures);
+}
+
+pub fn draw_health(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, health: Health, resolution: (u32, u32), font_textures: &HashMap<char, Texture>) {
+    let text = format!("health: {:.0}  armour: {:.0}", health.health.ceil(), health.armour.ceil());
+    render_string(&text, hud_line(resolution, -1), canvas, font_textures);
+}
+
+// Score, ammo counts and keys held, above the health line
+pub fn draw_inventory(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, inventory: &Inventory, resolution: (u32, u32), font_textures: &HashMap<char, Texture>) {
+    let mut ammo: Vec<String> = inventory.ammo.iter().map(|(name, count)| format!("{}: {}", name, count)).collect();
+    ammo.sort();
+    let mut keys: Vec<&str> = inventory.keys.iter().map(|k| k.name()).collect();
+    keys.sort();
+    let mut text = format!("score: {}", inventory.score);
+    for a in ammo {
+        text += &format!("  {}", a);
+    }
+    if !keys.is_empty() {
+        text += &format!("  keys: {}", keys.join(" "));
+    }
+    render_string(&text, hud_line(resolution, -2), canvas, font_textures);
+}
+
+// Red tint over the whole frame. amount: 0.0 (none) -> 1.0 (solid red)
+pub fn draw_damage_overlay(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, resolution: (u32, u32), amount: f64) {
+    if amount <= 0.0 {
+        return;
+    }
+    canvas.set_blend_mode(BlendMode::Blend);
+    canvas.set_draw_color(Color::RGBA(255, 0, 0, (amount.min(1.0) * 255.0) as u8));
+    canvas.fill_rect(Rect::new(0, 0, resolution.0, resolution.1)).unwrap();
+    canvas.set_blend_mode(BlendMode::None);
+}
+
+// Marks the screen centre, where shots go
+pub fn draw_crosshair(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, resolution: (u32, u32)) {
+    let centre_x = resolution.0 as i32 / 2;
+    let centre_y = resolution.1 as i32 / 2;
+    canvas.set_draw_color(Color::RGB(255, 255, 0));
+    canvas.fill_rect(Rect::new(centre_x - 6, centre_y - 1, 12, 2)).unwrap();
+    canvas.fill_rect(Rect::new(centre_x - 1, centre_y - 6, 2, 12)).unwrap();
+}
+
+// Whether the entity with this id counts towards the kill total
+fn is_enemy(game: &Game, id: u32) -> bool {
+    let entities = game.entities();
+    return entities.find_by_id(id).map_or(false, |h| entities[h].tags.iter().any(|t| t == ENEMY_TAG));
 }
 
 pub fn get_fps (frame_time: f64) -> f64 {
@@ -127,7 +478,7 @@ pub fn get_fps (frame_time: f64) -> f64 {
 fn generate_font_textures (texture_creator: &sdl2::render::TextureCreator<WindowContext>) -> HashMap<char, Texture> {
     let mut textures = HashMap::new();
     let ttf = sdl2::ttf::init().unwrap();
-    let font = ttf.load_font("./data/fonts/ARIAL.TTF", 35).unwrap();
+    let font = ttf.load_font("./data/fonts/ARIAL.TTF", FONT_SIZE).unwrap();
     let valid_chars = "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ .:";
     for c in valid_chars.chars() {
         let surface = font.render(str::from_utf8(&[(c as u8)]).unwrap()).blended(Color::RGBA(255, 255, 0, 255)).unwrap();
@@ -139,14 +490,21 @@ fn generate_font_textures (texture_creator: &sdl2::render::TextureCreator<Window
 }
 
 fn render_string (s: &str, position: Rect, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, font_textures: &HashMap<char, Texture>) {
+    // Spacing is in font pixels, scale it along with the glyphs
+    let scale = position.height() as f64 / FONT_SIZE as f64;
     let mut start_x = position.x;
     for c in s.chars() {
         if c == ' ' {
-            start_x += 10;
+            start_x += (10.0 * scale) as i32;
             continue;
         }
-        let width = &font_textures.get(&c).unwrap().query().width;
-        canvas.copy(&font_textures.get(&c).unwrap(), None, Rect::new(start_x, position.y, position.width(), position.height())).unwrap();
-        start_x += *width as i32 + 5;
+        // Messages come from data files, skip anything the font wasn't generated for
+        let texture = match font_textures.get(&c) {
+            Some(t) => t,
+            None => continue,
+        };
+        let width = texture.query().width;
+        canvas.copy(texture, None, Rect::new(start_x, position.y, position.width(), position.height())).unwrap();
+        start_x += ((width + 5) as f64 * scale) as i32;
     }
 }
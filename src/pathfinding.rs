@@ -0,0 +1,302 @@
+use cgmath::Vector2;
+use cgmath::InnerSpace;
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use crate::data::WorldMap;
+use crate::data::DoorState;
+
+// Extra cost of walking through a cell with a collidable entity in it, so paths go round if they can
+const ENTITY_COST: f64 = 4.0;
+// Cells looked at before giving up, keeps big open maps cheap
+const MAX_SEARCH_NODES: usize = 4096;
+// Path cells ahead of the current one that steering may cut straight to
+const LOOKAHEAD: usize = 3;
+
+// Cell in the open set, ordered so the BinaryHeap pops the lowest estimated total cost first
+struct Node {
+    cell: (i32, i32),
+    estimate: f64,
+}
+
+impl PartialEq for Node {
+    fn eq(&self, other: &Node) -> bool {
+        self.estimate == other.estimate
+    }
+}
+
+impl Eq for Node {}
+
+impl Ord for Node {
+    fn cmp(&self, other: &Node) -> Ordering {
+        other.estimate.partial_cmp(&self.estimate).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Node {
+    fn partial_cmp(&self, other: &Node) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// A* over the map grid. Paths found during a simulation step are cached until the next begin_frame,
+// so entities chasing the same target share one search
+pub struct Pathfinder {
+    occupied: HashSet<(i32, i32)>, // Cells with a collidable entity in them
+    cache: HashMap<((i32, i32), (i32, i32)), Option<Vec<Vector2<i32>>>>,
+}
+
+impl Pathfinder {
+    pub fn new() -> Pathfinder {
+        Pathfinder {
+            occupied: HashSet::new(),
+            cache: HashMap::new(),
+        }
+    }
+
+    // Start of a simulation step: forget old paths and note where entities are standing now
+    pub fn begin_frame(&mut self, occupied: HashSet<(i32, i32)>) {
+        self.occupied = occupied;
+        self.cache.clear();
+    }
+
+    // Cells to walk through to get from start to goal, not including the start cell.
+    // None if the goal can't be reached
+    pub fn find_path(&mut self, map: &WorldMap, start: Vector2<f64>, goal: Vector2<f64>) -> Option<Vec<Vector2<i32>>> {
+        let start = (start.x.floor() as i32, start.y.floor() as i32);
+        let goal = (goal.x.floor() as i32, goal.y.floor() as i32);
+        if let Some(path) = self.cache.get(&(start, goal)) {
+            return path.clone();
+        }
+        let path = self.search(map, start, goal);
+        self.cache.insert((start, goal), path.clone());
+        return path;
+    }
+
+    fn search(&self, map: &WorldMap, start: (i32, i32), goal: (i32, i32)) -> Option<Vec<Vector2<i32>>> {
+        if !passable(map, goal.0, goal.1) {
+            return None;
+        }
+        let mut open = BinaryHeap::new();
+        let mut came_from: HashMap<(i32, i32), (i32, i32)> = HashMap::new();
+        let mut cost: HashMap<(i32, i32), f64> = HashMap::new();
+        cost.insert(start, 0.0);
+        open.push(Node { cell: start, estimate: heuristic(start, goal) });
+
+        let mut searched = 0;
+        while let Some(Node { cell, .. }) = open.pop() {
+            if cell == goal {
+                let mut path = vec![];
+                let mut curr = goal;
+                while curr != start {
+                    path.push(Vector2::new(curr.0, curr.1));
+                    curr = came_from[&curr];
+                }
+                path.reverse();
+                return Some(path);
+            }
+            searched += 1;
+            if searched > MAX_SEARCH_NODES {
+                return None;
+            }
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    if dx == 0 && dy == 0 {
+                        continue;
+                    }
+                    let next = (cell.0 + dx, cell.1 + dy);
+                    if !passable(map, next.0, next.1) {
+                        continue;
+                    }
+                    // Diagonals can't cut a corner, both cells beside the move have to be free
+                    if dx != 0 && dy != 0 && (!passable(map, cell.0 + dx, cell.1) || !passable(map, cell.0, cell.1 + dy)) {
+                        continue;
+                    }
+                    let mut step = if dx != 0 && dy != 0 { std::f64::consts::SQRT_2 } else { 1.0 };
+                    if next != goal && self.occupied.contains(&next) {
+                        step += ENTITY_COST;
+                    }
+                    let next_cost = cost[&cell] + step;
+                    if cost.get(&next).map_or(true, |c| next_cost < *c) {
+                        cost.insert(next, next_cost);
+                        came_from.insert(next, cell);
+                        open.push(Node { cell: next, estimate: next_cost + heuristic(next, goal) });
+                    }
+                }
+            }
+        }
+        return None;
+    }
+}
+
+// Walls, closed doors and sliding pushwalls block paths. Doors that are opening or open don't,
+// whoever's following the path can wait for them
+fn passable(map: &WorldMap, x: i32, y: i32) -> bool {
+    if !map.in_bounds(x, y) || map.moving_pushwall_at(x, y).is_some() {
+        return false;
+    }
+    let cell = map.get_cell(x as u32, y as u32);
+    return match cell.door {
+        Some(d) => map.doors[d].state != DoorState::Closed,
+        None => cell.wall_tex == 0,
+    };
+}
+
+// Octile distance, exact on an empty 8-connected grid
+fn heuristic(a: (i32, i32), b: (i32, i32)) -> f64 {
+    let dx = (a.0 - b.0).abs() as f64;
+    let dy = (a.1 - b.1).abs() as f64;
+    return dx.max(dy) + (std::f64::consts::SQRT_2 - 1.0) * dx.min(dy);
+}
+
+// Point to head for along a path: the furthest of the next few cells that's in a straight line of sight,
+// so entities cut across open space instead of zig zagging between cell centres. The last cell is the goal itself
+pub fn next_waypoint(map: &WorldMap, pos: Vector2<f64>, path: &[Vector2<i32>], goal: Vector2<f64>) -> Vector2<f64> {
+    let centre = |i: usize| match i == path.len() - 1 {
+        true => goal,
+        false => Vector2::new(path[i].x as f64 + 0.5, path[i].y as f64 + 0.5),
+    };
+    let mut waypoint = centre(0);
+    for i in 1..path.len().min(LOOKAHEAD + 1) {
+        if !map.line_of_sight(pos, centre(i)) {
+            break;
+        }
+        waypoint = centre(i);
+    }
+    return waypoint;
+}
+
+// Turn dir towards target_dir by at most max_turn radians
+pub fn steer(dir: Vector2<f64>, target_dir: Vector2<f64>, max_turn: f64) -> Vector2<f64> {
+    if dir.magnitude2() == 0.0 {
+        return target_dir;
+    }
+    let dir = dir.normalize();
+    let angle = dir.perp_dot(target_dir).atan2(dir.dot(target_dir));
+    if angle.abs() <= max_turn {
+        return target_dir;
+    }
+    let turn = max_turn * angle.signum();
+    return Vector2::new(
+        dir.x * turn.cos() - dir.y * turn.sin(),
+        dir.x * turn.sin() + dir.y * turn.cos(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn centre(x: i32, y: i32) -> Vector2<f64> {
+        Vector2::new(x as f64 + 0.5, y as f64 + 0.5)
+    }
+
+    fn path(map: &WorldMap, occupied: &[(i32, i32)], from: (i32, i32), to: (i32, i32)) -> Option<Vec<(i32, i32)>> {
+        let mut pathfinder = Pathfinder::new();
+        pathfinder.begin_frame(occupied.iter().cloned().collect());
+        let path = pathfinder.find_path(map, centre(from.0, from.1), centre(to.0, to.1))?;
+        return Some(path.iter().map(|c| (c.x, c.y)).collect());
+    }
+
+    // Open room split by a wall with a gap at the far end, so the way round is long
+    fn split_room(size: usize) -> WorldMap {
+        let mut rows = vec![];
+        for y in 0..size {
+            let row: String = (0..size).map(|x| {
+                let border = x == 0 || y == 0 || x == size - 1 || y == size - 1;
+                let divider = x == size / 2 && y < size - 2;
+                if border || divider { '#' } else { '.' }
+            }).collect();
+            rows.push(row);
+        }
+        let rows: Vec<&str> = rows.iter().map(|r| r.as_str()).collect();
+        return WorldMap::from_rows(&rows);
+    }
+
+    #[test]
+    fn start_is_goal() {
+        let map = WorldMap::from_rows(&["###", "#.#", "###"]);
+        let mut pathfinder = Pathfinder::new();
+        let path = pathfinder.find_path(&map, Vector2::new(1.2, 1.3), Vector2::new(1.9, 1.1));
+        assert_eq!(path, Some(vec![]));
+    }
+
+    #[test]
+    fn straight_line() {
+        let map = WorldMap::from_rows(&["######", "#....#", "######"]);
+        assert_eq!(path(&map, &[], (1, 1), (4, 1)), Some(vec![(2, 1), (3, 1), (4, 1)]));
+    }
+
+    #[test]
+    fn no_corner_cutting() {
+        let map = WorldMap::from_rows(&[
+            "#####",
+            "#...#",
+            "###.#",
+            "#...#",
+            "#####",
+        ]);
+        assert_eq!(path(&map, &[], (1, 1), (1, 3)), Some(vec![(2, 1), (3, 1), (3, 2), (3, 3), (2, 3), (1, 3)]));
+    }
+
+    #[test]
+    fn unreachable_goal() {
+        let map = WorldMap::from_rows(&[
+            "#######",
+            "#..#..#",
+            "#######",
+        ]);
+        assert_eq!(path(&map, &[], (1, 1), (5, 1)), None);
+        // Goal inside a wall
+        assert_eq!(path(&map, &[], (1, 1), (3, 1)), None);
+    }
+
+    #[test]
+    fn closed_doors_block() {
+        let mut map = WorldMap::from_rows(&[
+            "#####",
+            "#.D.#",
+            "#####",
+        ]);
+        assert_eq!(path(&map, &[], (1, 1), (3, 1)), None);
+        // Opening is enough, whoever follows the path waits for it
+        map.doors[0].state = DoorState::Opening;
+        assert_eq!(path(&map, &[], (1, 1), (3, 1)), Some(vec![(2, 1), (3, 1)]));
+        map.doors[0].state = DoorState::Open;
+        assert_eq!(path(&map, &[], (1, 1), (3, 1)), Some(vec![(2, 1), (3, 1)]));
+    }
+
+    #[test]
+    fn goes_round_occupied_cells() {
+        let map = WorldMap::from_rows(&[
+            "#######",
+            "#.....#",
+            "#.....#",
+            "#######",
+        ]);
+        assert_eq!(path(&map, &[], (1, 1), (5, 1)), Some(vec![(2, 1), (3, 1), (4, 1), (5, 1)]));
+        let path = path(&map, &[(3, 1)], (1, 1), (5, 1)).unwrap();
+        assert!(!path.contains(&(3, 1)), "{:?}", path);
+        assert_eq!(path.last(), Some(&(5, 1)));
+    }
+
+    #[test]
+    fn walks_through_occupied_cells_if_it_has_to() {
+        let map = WorldMap::from_rows(&["#######", "#.....#", "#######"]);
+        // Whoever is standing on the goal doesn't add to the cost either
+        assert_eq!(path(&map, &[(3, 1), (5, 1)], (1, 1), (5, 1)), Some(vec![(2, 1), (3, 1), (4, 1), (5, 1)]));
+    }
+
+    #[test]
+    fn gives_up_after_max_search_nodes() {
+        // Same layout, small enough to search fully
+        let small = split_room(20);
+        assert!(path(&small, &[], (1, 1), (18, 1)).is_some());
+        // Over 10000 cells on the near side of the wall, more than MAX_SEARCH_NODES
+        let big = split_room(150);
+        assert_eq!(path(&big, &[], (1, 1), (148, 1)), None);
+    }
+}
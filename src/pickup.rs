@@ -0,0 +1,164 @@
+use serde::{Serialize, Deserialize};
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use crate::damage::Health;
+
+// Most of each ammo type the player can carry
+pub const MAX_AMMO: u32 = 99;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyColour {
+    Red,
+    Blue,
+    Yellow,
+}
+
+impl KeyColour {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyColour::Red => "red",
+            KeyColour::Blue => "blue",
+            KeyColour::Yellow => "yellow",
+        }
+    }
+}
+
+// What collecting a pickup does
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PickupEffect {
+    Health { amount: f64 },
+    Ammo { ammo_type: String, amount: u32 },
+    Key { colour: KeyColour },
+    Treasure { score: u32 },
+}
+
+// Pickup settings in an entity template
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PickupTmpl {
+    pub effect: PickupEffect,
+    #[serde(default)]
+    pub message: String, // Shown on the HUD when collected
+    #[serde(default)]
+    pub sound: String, // Sound event name
+}
+
+// Everything the player has collected apart from health
+#[derive(Debug, Clone)]
+pub struct Inventory {
+    pub ammo: HashMap<String, u32>,
+    pub keys: HashSet<KeyColour>,
+    pub score: u32,
+}
+
+impl Inventory {
+    pub fn new() -> Inventory {
+        Inventory {
+            ammo: HashMap::new(),
+            keys: HashSet::new(),
+            score: 0,
+        }
+    }
+
+    pub fn has_key(&self, colour: KeyColour) -> bool {
+        self.keys.contains(&colour)
+    }
+
+    // Apply a pickup if it would do anything. Returns false (and changes nothing) when it's of no use,
+    // e.g. health at full health, so it's left for later
+    pub fn collect(&mut self, effect: &PickupEffect, health: &mut Health) -> bool {
+        match effect {
+            PickupEffect::Health { amount } => {
+                if health.health >= health.max_health {
+                    return false;
+                }
+                health.health = (health.health + amount).min(health.max_health);
+            },
+            PickupEffect::Ammo { ammo_type, amount } => {
+                let count = self.ammo.entry(ammo_type.clone()).or_insert(0);
+                if *count >= MAX_AMMO {
+                    return false;
+                }
+                *count = count.saturating_add(*amount).min(MAX_AMMO);
+            },
+            PickupEffect::Key { colour } => {
+                return self.keys.insert(*colour);
+            },
+            PickupEffect::Treasure { score } => {
+                self.score = self.score.saturating_add(*score);
+            },
+        }
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ammo(amount: u32) -> PickupEffect {
+        PickupEffect::Ammo { ammo_type: "bullets".to_string(), amount: amount }
+    }
+
+    #[test]
+    fn health_only_when_hurt() {
+        let mut inventory = Inventory::new();
+        let mut health = Health::new(100.0, 0.0, 0.0);
+        let medkit = PickupEffect::Health { amount: 25.0 };
+        assert!(!inventory.collect(&medkit, &mut health));
+        assert_eq!(health.health, 100.0);
+
+        health.health = 90.0;
+        assert!(inventory.collect(&medkit, &mut health));
+        assert_eq!(health.health, 100.0);
+        health.health = 50.0;
+        assert!(inventory.collect(&medkit, &mut health));
+        assert_eq!(health.health, 75.0);
+    }
+
+    #[test]
+    fn ammo_up_to_the_cap() {
+        let mut inventory = Inventory::new();
+        let mut health = Health::new(100.0, 0.0, 0.0);
+        assert!(inventory.collect(&ammo(10), &mut health));
+        assert_eq!(inventory.ammo["bullets"], 10);
+        assert!(inventory.collect(&ammo(95), &mut health));
+        assert_eq!(inventory.ammo["bullets"], MAX_AMMO);
+        assert!(!inventory.collect(&ammo(10), &mut health));
+        assert_eq!(inventory.ammo["bullets"], MAX_AMMO);
+    }
+
+    #[test]
+    fn huge_ammo_amount_doesnt_overflow() {
+        let mut inventory = Inventory::new();
+        let mut health = Health::new(100.0, 0.0, 0.0);
+        assert!(inventory.collect(&ammo(1), &mut health));
+        assert!(inventory.collect(&ammo(u32::MAX), &mut health));
+        assert_eq!(inventory.ammo["bullets"], MAX_AMMO);
+    }
+
+    #[test]
+    fn duplicate_key() {
+        let mut inventory = Inventory::new();
+        let mut health = Health::new(100.0, 0.0, 0.0);
+        let key = PickupEffect::Key { colour: KeyColour::Blue };
+        assert!(inventory.collect(&key, &mut health));
+        assert!(inventory.has_key(KeyColour::Blue));
+        assert!(!inventory.has_key(KeyColour::Red));
+        assert!(!inventory.collect(&key, &mut health));
+        assert_eq!(inventory.keys.len(), 1);
+    }
+
+    #[test]
+    fn treasure_always_collected() {
+        let mut inventory = Inventory::new();
+        let mut health = Health::new(100.0, 0.0, 0.0);
+        let treasure = PickupEffect::Treasure { score: 100 };
+        assert!(inventory.collect(&treasure, &mut health));
+        assert!(inventory.collect(&treasure, &mut health));
+        assert_eq!(inventory.score, 200);
+    }
+}
@@ -0,0 +1,44 @@
+use serde::{Serialize, Deserialize};
+
+use cgmath::Vector2;
+
+use crate::damage::DamageTarget;
+
+// Projectile settings in an entity template
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProjectileTmpl {
+    pub speed: f64, // Cells per second
+    pub lifetime: f64, // Seconds before it bursts by itself
+    pub damage: f64,
+    pub radius: f64, // For hitting entities and the player
+    #[serde(default)]
+    pub impact_animation: String, // Played where it hits before it's removed, empty for none
+}
+
+// Per entity projectile state
+#[derive(Debug, Clone)]
+pub struct Projectile {
+    pub speed: f64,
+    pub velocity: Vector2<f64>, // Set when it's launched
+    pub lifetime: f64, // Seconds left
+    pub damage: f64,
+    pub radius: f64,
+    pub owner: DamageTarget, // Never hits whoever fired it
+    pub impact_animation: String,
+    pub exploded: bool, // Hit something, only the impact animation is left
+}
+
+impl Projectile {
+    pub fn new(tmpl: &ProjectileTmpl) -> Projectile {
+        Projectile {
+            speed: tmpl.speed,
+            velocity: Vector2::new(0.0, 0.0),
+            lifetime: tmpl.lifetime,
+            damage: tmpl.damage,
+            radius: tmpl.radius,
+            owner: DamageTarget::Player,
+            impact_animation: tmpl.impact_animation.clone(),
+            exploded: false,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+extern crate sdl2;
+extern crate image;
+
+use sdl2::render::Canvas;
+use sdl2::render::Texture;
+use sdl2::video::Window;
+use sdl2::rect::Rect;
+
+use std::error::Error;
+
+use crate::textures::RawTexture;
+use crate::textures::TextureManager;
+use crate::sprites::SpriteManager;
+
+// Anything the game can draw a frame onto.
+// The raycaster only ever needs these 3 operations, so the SDL canvas and an in-memory framebuffer are interchangeable
+pub trait RenderTarget {
+    // Replace the whole frame with a full screen RGBA buffer (the floor and ceiling pass)
+    fn draw_buffer(&mut self, data: &[u8]);
+    // Copy (and scale) a region of a wall texture. dark: use the darkened version for y-sides
+    fn copy_wall(&mut self, textures: &TextureManager, tex_num: u32, dark: bool, src: Rect, dst: Rect);
+    // Alpha blend (and scale) a region of a sprite sheet
+    fn copy_sprite(&mut self, sprites: &SpriteManager, tex_id: &str, src: Rect, dst: Rect);
+}
+
+// Draws onto the SDL window. Floor/ceiling buffer is streamed through floor_texture
+pub struct SdlTarget<'r, 't> {
+    canvas: &'r mut Canvas<Window>,
+    floor_texture: &'r mut Texture<'t>,
+}
+
+impl<'r, 't> SdlTarget<'r, 't> {
+    pub fn new(canvas: &'r mut Canvas<Window>, floor_texture: &'r mut Texture<'t>) -> SdlTarget<'r, 't> {
+        SdlTarget {
+            canvas: canvas,
+            floor_texture: floor_texture,
+        }
+    }
+}
+
+impl<'r, 't> RenderTarget for SdlTarget<'r, 't> {
+    fn draw_buffer(&mut self, data: &[u8]) {
+        // Faster than texture.update?
+        self.floor_texture.with_lock(None, |dat, _| {
+            dat.copy_from_slice(data);
+        }).unwrap();
+
+        self.canvas.copy(self.floor_texture, None, None).unwrap();
+    }
+
+    fn copy_wall(&mut self, textures: &TextureManager, tex_num: u32, dark: bool, src: Rect, dst: Rect) {
+        let texture = match dark {
+            false => textures.get_tex(tex_num),
+            true => textures.get_dark_tex(tex_num),
+        };
+        self.canvas.copy(texture, src, dst).unwrap();
+    }
+
+    fn copy_sprite(&mut self, sprites: &SpriteManager, tex_id: &str, src: Rect, dst: Rect) {
+        self.canvas.copy(sprites.get_texture(tex_id).unwrap(), src, dst).unwrap();
+    }
+}
+
+// Plain RGBA framebuffer in memory. Lets us render frames with no window at all (tests, screenshots, CI)
+pub struct FrameBuffer {
+    pub width: u32,
+    pub height: u32,
+    pixels: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: u32, height: u32) -> FrameBuffer {
+        FrameBuffer {
+            width: width,
+            height: height,
+            pixels: vec![0; (width * height * 4) as usize],
+        }
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn save_png(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        image::save_buffer(path, &self.pixels, self.width, self.height, image::ColorType::Rgba8)?;
+        return Ok(());
+    }
+
+    // Nearest neighbour scaled copy of src (in texture space) to dst (in screen space), clipped to both.
+    // blend: alpha blend onto what's already there, otherwise overwrite as opaque
+    fn blit(&mut self, tex: &RawTexture, src: Rect, dst: Rect, darken: bool, blend: bool) {
+        for dy in 0..dst.height() as i32 {
+            let y = dst.y() + dy;
+            if y < 0 || y >= self.height as i32 {
+                continue;
+            }
+            let ty = src.y() + (dy as i64 * src.height() as i64 / dst.height() as i64) as i32;
+            if ty < 0 || ty >= tex.height as i32 {
+                continue;
+            }
+            for dx in 0..dst.width() as i32 {
+                let x = dst.x() + dx;
+                if x < 0 || x >= self.width as i32 {
+                    continue;
+                }
+                let tx = src.x() + (dx as i64 * src.width() as i64 / dst.width() as i64) as i32;
+                if tx < 0 || tx >= tex.width as i32 {
+                    continue;
+                }
+                let s = ((ty as u32 * tex.width + tx as u32) * 4) as usize;
+                let d = ((y as u32 * self.width + x as u32) * 4) as usize;
+                let mut texel = [tex.pixels[s], tex.pixels[s + 1], tex.pixels[s + 2], tex.pixels[s + 3]];
+                if darken {
+                    // Same as the dark textures: divide color by 2
+                    for c in texel.iter_mut().take(3) {
+                        *c /= 2;
+                    }
+                }
+                if blend {
+                    let alpha = texel[3] as u32;
+                    for c in 0..3 {
+                        self.pixels[d + c] = ((texel[c] as u32 * alpha + self.pixels[d + c] as u32 * (255 - alpha)) / 255) as u8;
+                    }
+                } else {
+                    self.pixels[d..d + 3].copy_from_slice(&texel[0..3]);
+                }
+                self.pixels[d + 3] = 255;
+            }
+        }
+    }
+}
+
+impl RenderTarget for FrameBuffer {
+    fn draw_buffer(&mut self, data: &[u8]) {
+        self.pixels.copy_from_slice(data);
+        // Buffer is drawn unblended, so treat it as opaque
+        for alpha in self.pixels.iter_mut().skip(3).step_by(4) {
+            *alpha = 255;
+        }
+    }
+
+    fn copy_wall(&mut self, textures: &TextureManager, tex_num: u32, dark: bool, src: Rect, dst: Rect) {
+        self.blit(textures.get_raw_tex(tex_num), src, dst, dark, false);
+    }
+
+    fn copy_sprite(&mut self, sprites: &SpriteManager, tex_id: &str, src: Rect, dst: Rect) {
+        self.blit(sprites.get_raw_texture(tex_id).unwrap(), src, dst, false, true);
+    }
+}
@@ -3,6 +3,17 @@ extern crate glob;
 
 use crate::animation::Animation;
 use crate::animation::AnimationManager;
+use crate::textures::RawTexture;
+use crate::damage::Health;
+use crate::damage::DamageEvent;
+use crate::damage::DamageTarget;
+use crate::ai::Ai;
+use crate::ai::AiTmpl;
+use crate::ai::AiState;
+use crate::ai::PAIN_TIME;
+use crate::projectile::Projectile;
+use crate::projectile::ProjectileTmpl;
+use crate::pickup::PickupTmpl;
 
 use image::GenericImageView;
 
@@ -37,17 +48,35 @@ pub struct Sprite {
     pub v_move: i32, // vertical move
     #[serde(default)]
     pub rotating: bool,
+    // Frame grid of sheets that don't rotate. Rotating sheets are always 8 columns by 7 rows
+    #[serde(default = "default_frames")]
+    pub columns: u32,
+    #[serde(default = "default_frames")]
+    pub rows: u32,
+}
+
+fn default_frames() -> u32 {
+    1
 }
 
 #[derive(Clone, Debug)]
 pub struct Entity {
     pub id: u32,
     pub name: String,
+    pub tags: Vec<String>,
     pub sprite: Sprite,
     pub pos: Vector3<f64>,
+    pub prev_pos: Vector3<f64>, // Position at the start of the last simulation step, for interpolation
     pub dir: Vector2<f64>,
     pub collidable: bool,
     pub collision_radius: f64,
+    pub mass: f64, // 0.0: can't be pushed
+    pub health: Health, // max_health 0.0: can't be shot
+    pub pain_animation: String, // Played when hurt but not killed, empty for none
+    pub death_animation: String,
+    pub ai: Option<Ai>, // None for scenery
+    pub projectile: Option<Projectile>,
+    pub pickup: Option<PickupTmpl>,
     pub animation: Option<Animation>,
     pub dead: bool,
 }
@@ -57,10 +86,40 @@ pub struct Entity {
 pub struct EntityTmpl {
     pub name: String,
     pub sprite_name: String,
+    // For finding entities by kind at runtime, e.g. "enemy"
+    #[serde(default)]
+    pub tags: Vec<String>,
     #[serde(default)]
     pub collidable: bool,
     #[serde(default)]
     pub collision_radius: f64,
+    // Collidable entities with mass can be pushed around by the player. Heavier ones move less
+    #[serde(default)]
+    pub mass: f64,
+    // Entities with health can be shot, and die when it runs out
+    #[serde(default)]
+    pub health: f64,
+    #[serde(default)]
+    pub armour: f64,
+    // Fraction of all damage ignored, 0.0 -> 1.0
+    #[serde(default)]
+    pub resistance: f64,
+    #[serde(default)]
+    pub pain_animation: String,
+    #[serde(default = "default_death_animation")]
+    pub death_animation: String,
+    #[serde(default)]
+    pub ai: Option<AiTmpl>,
+    // Spawned at runtime and flies until it hits something
+    #[serde(default)]
+    pub projectile: Option<ProjectileTmpl>,
+    // Collected when the player walks over it
+    #[serde(default)]
+    pub pickup: Option<PickupTmpl>,
+}
+
+fn default_death_animation() -> String {
+    String::from("die")
 }
 
 pub struct EntityManager<'a> {
@@ -94,17 +153,49 @@ impl<'a> EntityManager<'_> {
         return Ok(self);
     }
 
+    // Whether create_entity will succeed for this name (template and its sprite both exist)
+    pub fn has_entity(&self, name: &str) -> bool {
+        match self.entity_tmpls.get(name) {
+            None => false,
+            Some(tmpl) => self.sprite_manager.get_sprite(&tmpl.sprite_name).is_some(),
+        }
+    }
+
+    pub fn get_template(&self, name: &str) -> Option<&EntityTmpl> {
+        self.entity_tmpls.get(name)
+    }
+
+    // Sprite the named template is drawn with
+    pub fn get_sprite(&self, name: &str) -> Option<&Sprite> {
+        self.sprite_manager.get_sprite(&self.entity_tmpls.get(name)?.sprite_name)
+    }
+
+    // Templates that aren't in data/entities, for tests
+    #[cfg(test)]
+    pub fn add_template(&mut self, tmpl: EntityTmpl) {
+        self.entity_tmpls.insert(tmpl.name.clone(), tmpl);
+    }
+
     pub fn create_entity(&mut self, name: &str) -> Option<Entity> {
         let ent_tmpl = self.entity_tmpls.get(name)?;
         let sprite = self.sprite_manager.get_sprite(&ent_tmpl.sprite_name)?;
         let ent = Entity {
             id: self.id_counter,
             name: String::from(name),
+            tags: ent_tmpl.tags.clone(),
             sprite: sprite.clone(),
             pos: Vector3::new(0.0, 0.0, 0.0),
+            prev_pos: Vector3::new(0.0, 0.0, 0.0),
             dir: Vector2::new(0.0, 0.0),
             collidable: ent_tmpl.collidable,
             collision_radius: ent_tmpl.collision_radius,
+            mass: ent_tmpl.mass,
+            health: Health::new(ent_tmpl.health, ent_tmpl.armour, ent_tmpl.resistance),
+            pain_animation: ent_tmpl.pain_animation.clone(),
+            death_animation: ent_tmpl.death_animation.clone(),
+            ai: ent_tmpl.ai.as_ref().map(Ai::new),
+            projectile: ent_tmpl.projectile.as_ref().map(Projectile::new),
+            pickup: ent_tmpl.pickup.clone(),
             animation: None,
             dead: false,
         };
@@ -115,6 +206,7 @@ impl<'a> EntityManager<'_> {
 
 pub struct SpriteManager<'a> {
     sprite_textures: HashMap<String, Texture<'a>>,
+    raw_textures: HashMap<String, RawTexture>,
     sprites: HashMap<String, Sprite>,
 }
 
@@ -122,13 +214,15 @@ impl<'a> SpriteManager<'a> {
     pub fn new() -> SpriteManager<'a> {
         SpriteManager {
             sprite_textures: HashMap::new(),
+            raw_textures: HashMap::new(),
             sprites: HashMap::new(),
         }
     }
 
-    pub fn init(&mut self, creator: &'a TextureCreator<sdl2::video::WindowContext>) -> Result<&Self, Box<dyn Error>> {
+    // Load sprite metadata and raw pixel data only. Enough for headless rendering, no SDL video needed
+    pub fn load(&mut self) -> Result<&Self, Box<dyn Error>> {
         let mut map = HashMap::new();
-        let mut tex_map = HashMap::new();
+        let mut raw_map = HashMap::new();
         let meta_paths = glob("./data/textures/sprites/*_meta.json")?
             .filter_map(Result::ok);
         for meta in meta_paths {
@@ -138,22 +232,36 @@ impl<'a> SpriteManager<'a> {
                 file.read_to_string(&mut data)?;
                 let mut sprite: Sprite = serde_json::from_str(&data)?;
 
-                // Init sprite_textures map
+                // Init raw_textures map
                 let sprite_name = &sprite.name;
                 let path = format!("./data/textures/sprites/{}.png", sprite_name);
                 let img = image::open(path)?;
                 let dim = img.dimensions(); // (width, height)
                 sprite.width = dim.0;
                 sprite.height = dim.1;
-                let img_raw = img.to_rgba().into_vec();
-                let mut texture = creator.create_texture_static(PixelFormatEnum::RGBA32, sprite.width, sprite.height).unwrap();
-                texture.update(None, &img_raw, (sprite.width * 4) as usize)?;
-                texture.set_blend_mode(BlendMode::Blend);
-                tex_map.insert(sprite.tex_id.clone(), texture);
+                raw_map.insert(sprite.tex_id.clone(), RawTexture {
+                    width: dim.0,
+                    height: dim.1,
+                    pixels: img.to_rgba().into_vec(),
+                });
 
                 map.insert(sprite.name.clone(), sprite);
         }
         self.sprites = map;
+        self.raw_textures = raw_map;
+
+        return Ok(self);
+    }
+
+    pub fn init(&mut self, creator: &'a TextureCreator<sdl2::video::WindowContext>) -> Result<&Self, Box<dyn Error>> {
+        self.load()?;
+        let mut tex_map = HashMap::new();
+        for (tex_id, raw) in &self.raw_textures {
+            let mut texture = creator.create_texture_static(PixelFormatEnum::RGBA32, raw.width, raw.height).unwrap();
+            texture.update(None, &raw.pixels, (raw.width * 4) as usize)?;
+            texture.set_blend_mode(BlendMode::Blend);
+            tex_map.insert(tex_id.clone(), texture);
+        }
         self.sprite_textures = tex_map;
 
         return Ok(self);
@@ -163,9 +271,19 @@ impl<'a> SpriteManager<'a> {
         self.sprite_textures.get(id)
     }
 
+    pub fn get_raw_texture(&self, id: &str) -> Option<&RawTexture> {
+        self.raw_textures.get(id)
+    }
+
     pub fn get_sprite(&self, id: &str) -> Option<&Sprite> {
         self.sprites.get(id)
     }
+
+    // Sprite metadata that isn't in data/textures/sprites, for tests
+    #[cfg(test)]
+    pub fn add_sprite(&mut self, sprite: Sprite) {
+        self.sprites.insert(sprite.name.clone(), sprite);
+    }
 }
 
 impl Entity {
@@ -182,13 +300,18 @@ impl Entity {
         if self.sprite.rotating {
             width = width / 8;
             height = height / 7 + 1;
-            if !self.dead {
+            // Only directional animations (and no animation) pick a column by viewing angle
+            let directional = self.animation.as_ref().map_or(true, |a| a.directional);
+            if !self.dead && directional {
                 let step = 2.0 * std::f64::consts::PI / 8.0;
                 let step_num = ((angle + std::f64::consts::PI) / step) as i32;
                 let img_step = self.sprite.width as i32 / 8;
 
                 x = img_step * step_num;
             }
+        } else {
+            width = width / self.sprite.columns;
+            height = height / self.sprite.rows;
         }
 
         let y = match &self.animation {
@@ -237,14 +360,66 @@ impl Entity {
         };
     }
 
+    // Take a hit, flinching with the pain animation or dying when health runs out
+    pub fn damage (&mut self, amount: f64, manager: &AnimationManager) -> DamageEvent {
+        let lost = match self.dead {
+            true => 0.0,
+            false => self.health.apply(amount),
+        };
+        let killed = !self.dead && self.health.is_dead();
+        if killed {
+            self.kill(manager);
+        } else if lost > 0.0 && self.ai.is_some() {
+            self.set_ai_state(AiState::Pain, PAIN_TIME, manager);
+        } else if lost > 0.0 && !self.pain_animation.is_empty() {
+            self.animation = manager.get_animation(&self.pain_animation);
+        }
+        return DamageEvent {
+            target: DamageTarget::Entity(self.id),
+            amount: lost,
+            killed: killed,
+        };
+    }
+
     pub fn kill (&mut self, manager: &AnimationManager) {
         self.dead = true;
         self.collidable = false;
-        self.animation = manager.get_animation("die");
+        self.animation = manager.get_animation(&self.death_animation);
+        if let Some(ai) = &mut self.ai {
+            ai.state = AiState::Dead;
+        }
+    }
+
+    // Switch AI state and start its animation. timer: how long timed states last
+    pub fn set_ai_state (&mut self, state: AiState, timer: f64, manager: &AnimationManager) {
+        let ai = match &mut self.ai {
+            Some(ai) => ai,
+            None => return,
+        };
+        ai.state = state;
+        ai.timer = timer;
+        let name = match (ai.animation(state), state) {
+            (Some(name), _) => name.clone(),
+            (None, AiState::Pain) => self.pain_animation.clone(),
+            (None, AiState::Dead) => self.death_animation.clone(),
+            (None, _) => String::new(),
+        };
+        // Don't restart an animation that's already playing (e.g. walking from patrol into chase)
+        if self.animation.as_ref().map_or(false, |a| a.name == name) {
+            return;
+        }
+        self.animation = match name.as_str() {
+            "" => None,
+            _ => manager.get_animation(&name),
+        };
     }
 
     pub fn revive (&mut self) {
         self.dead = false;
+        self.health.restore();
+        if let Some(ai) = &mut self.ai {
+            ai.state = ai.tmpl.start;
+        }
         self.animation = None;
     }
 }
@@ -11,8 +11,16 @@ use glob::glob;
 
 use image::GenericImageView;
 
+// RGBA pixels of a texture kept in memory, independent of any SDL renderer
+#[derive(Clone, Debug)]
+pub struct RawTexture {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
 pub struct TextureManager<'a> {
-    raw_textures: Vec<Vec<u8>>,
+    raw_textures: Vec<RawTexture>,
     textures: Vec<Texture<'a>>,
     dark_textures: Vec<Texture<'a>>,
 }
@@ -26,25 +34,37 @@ impl<'a> TextureManager<'a> {
         }
     }
 
-    pub fn init(&mut self, creator: &'a TextureCreator<sdl2::video::WindowContext>) -> Result<&Self, Box<dyn Error>> {
+    // Load raw pixel data only. Enough for headless rendering, no SDL video needed
+    pub fn load(&mut self) -> Result<&Self, Box<dyn Error>> {
         let tex_paths = glob("./data/textures/walls/*.png")?
             .filter_map(Result::ok);
         for path in tex_paths {
             let img = image::open(path)?;
-            let img_raw = img.to_rgba().into_vec();
             let dim = img.dimensions(); // (width, height)
-            let mut texture = creator.create_texture_static(PixelFormatEnum::RGBA32, dim.0, dim.1).unwrap();
-            texture.update(None, &img_raw, (dim.0 * 4) as usize)?;
+            self.raw_textures.push(RawTexture {
+                width: dim.0,
+                height: dim.1,
+                pixels: img.to_rgba().into_vec(),
+            });
+        }
+
+        return Ok(self);
+    }
+
+    pub fn init(&mut self, creator: &'a TextureCreator<sdl2::video::WindowContext>) -> Result<&Self, Box<dyn Error>> {
+        self.load()?;
+        for raw in &self.raw_textures {
+            let mut texture = creator.create_texture_static(PixelFormatEnum::RGBA32, raw.width, raw.height).unwrap();
+            texture.update(None, &raw.pixels, (raw.width * 4) as usize)?;
 
             // Divide color by 2 for dark texture
             let mut dark_bytes = vec![];
-            for byte in &img_raw {
+            for byte in &raw.pixels {
                 dark_bytes.push(byte / 2);
             }
-            let mut dark_texture = creator.create_texture_static(PixelFormatEnum::RGBA32, dim.0, dim.1).unwrap();
-            dark_texture.update(None, &dark_bytes, (dim.0 * 4) as usize).unwrap();
+            let mut dark_texture = creator.create_texture_static(PixelFormatEnum::RGBA32, raw.width, raw.height).unwrap();
+            dark_texture.update(None, &dark_bytes, (raw.width * 4) as usize).unwrap();
             // Push texture to each vector
-            self.raw_textures.push(img_raw);
             self.textures.push(texture);
             self.dark_textures.push(dark_texture);
         }
@@ -60,7 +80,11 @@ impl<'a> TextureManager<'a> {
         &self.dark_textures[index as usize]
     }
 
-    pub fn get_raw_tex(&self, index: u32) -> &Vec<u8> {
+    pub fn num_textures(&self) -> usize {
+        self.raw_textures.len()
+    }
+
+    pub fn get_raw_tex(&self, index: u32) -> &RawTexture {
         &self.raw_textures[index as usize]
     }
 }
@@ -0,0 +1,524 @@
+use std::error::Error;
+use std::fmt;
+use std::collections::HashSet;
+
+use crate::data::WorldMapJSON;
+use crate::textures::TextureManager;
+use crate::sprites::EntityManager;
+use crate::animation::AnimationManager;
+use crate::ai::AttackType;
+use crate::pickup::KeyColour;
+use crate::pickup::PickupEffect;
+
+// Everything a map can reference by index or name
+pub struct MapAssets<'v> {
+    pub textures: &'v TextureManager<'v>,
+    pub entities: &'v EntityManager<'v>,
+    pub animations: &'v AnimationManager,
+}
+
+// A single thing wrong with a map file.
+// Grid positions are given as row/column of the JSON grid, the way the designer sees them in the file
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapProblem {
+    EmptyMap,
+    BadViewDistance,
+    GridHeight { grid: &'static str, expected: u32, found: usize },
+    RaggedRow { grid: &'static str, row: usize, expected: u32, found: usize },
+    BadTexture { grid: &'static str, row: usize, column: usize, tex: i32 },
+    NoPlayerStart,
+    PlayerStartOutOfBounds { index: usize },
+    PlayerStartInWall { index: usize },
+    PlayerStartOnDoor { index: usize },
+    PlayerStartOnPushWall { index: usize },
+    PlayerStartNoDirection { index: usize },
+    EntityOutOfBounds { index: usize, name: String },
+    UnknownEntity { index: usize, name: String },
+    UnknownAnimation { index: usize, name: String, animation: String },
+    UnknownProjectile { index: usize, name: String, projectile: String },
+    UnknownImpactAnimation { index: usize, name: String, projectile: String, animation: String },
+    BadSpriteFrames { index: usize, name: String, sprite: String },
+    DoorOutOfBounds { index: usize },
+    DoorOnWall { index: usize },
+    DoorBadTexture { index: usize, tex: i32 },
+    DoorKeyMissing { index: usize, colour: KeyColour },
+    PushWallOutOfBounds { index: usize },
+    PushWallNotOnWall { index: usize },
+}
+
+// All the problems found in one map
+#[derive(Debug)]
+pub struct MapErrors {
+    pub map: String,
+    pub problems: Vec<MapProblem>,
+}
+
+impl fmt::Display for MapProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapProblem::EmptyMap => write!(f, "height and width must both be at least 1"),
+            MapProblem::BadViewDistance => write!(f, "view_distance must be more than 0"),
+            MapProblem::GridHeight { grid, expected, found } =>
+                write!(f, "{}: has {} rows, height is {}", grid, found, expected),
+            MapProblem::RaggedRow { grid, row, expected, found } =>
+                write!(f, "{} row {}: has {} columns, width is {}", grid, row, found, expected),
+            MapProblem::BadTexture { grid, row, column, tex } =>
+                write!(f, "{} row {}, column {}: texture {} doesn't exist", grid, row, column, tex),
+            MapProblem::NoPlayerStart => write!(f, "player_starts: map needs at least one player start"),
+            MapProblem::PlayerStartOutOfBounds { index } =>
+                write!(f, "player start {}: position is outside the map", index),
+            MapProblem::PlayerStartInWall { index } =>
+                write!(f, "player start {}: position is inside a wall", index),
+            MapProblem::PlayerStartOnDoor { index } =>
+                write!(f, "player start {}: position is on a door", index),
+            MapProblem::PlayerStartOnPushWall { index } =>
+                write!(f, "player start {}: position is on a pushwall", index),
+            MapProblem::PlayerStartNoDirection { index } =>
+                write!(f, "player start {}: dir_x and dir_y are both 0", index),
+            MapProblem::EntityOutOfBounds { index, name } =>
+                write!(f, "entity {} ({}): position is outside the map", index, name),
+            MapProblem::UnknownEntity { index, name } =>
+                write!(f, "entity {}: no entity template (or its sprite) named {}", index, name),
+            MapProblem::UnknownAnimation { index, name, animation } =>
+                write!(f, "entity {} ({}): no animation named {}", index, name, animation),
+            MapProblem::UnknownProjectile { index, name, projectile } =>
+                write!(f, "entity {} ({}): no projectile entity named {}", index, name, projectile),
+            MapProblem::UnknownImpactAnimation { index, name, projectile, animation } =>
+                write!(f, "entity {} ({}): projectile {} has no animation named {}", index, name, projectile, animation),
+            MapProblem::BadSpriteFrames { index, name, sprite } =>
+                write!(f, "entity {} ({}): sprite {} needs at least 1 column and 1 row", index, name, sprite),
+            MapProblem::DoorOutOfBounds { index } => write!(f, "door {}: position is outside the map", index),
+            MapProblem::DoorOnWall { index } => write!(f, "door {}: cell must be empty in wall_grid", index),
+            MapProblem::DoorBadTexture { index, tex } => write!(f, "door {}: texture {} doesn't exist", index, tex),
+            MapProblem::DoorKeyMissing { index, colour } =>
+                write!(f, "door {}: locked with the {} key, but no pickup on the map gives it", index, colour.name()),
+            MapProblem::PushWallOutOfBounds { index } => write!(f, "pushwall {}: position is outside the map", index),
+            MapProblem::PushWallNotOnWall { index } => write!(f, "pushwall {}: cell must be a wall in wall_grid", index),
+        }
+    }
+}
+
+impl fmt::Display for MapErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "map {} has {} problem(s):", self.map, self.problems.len())?;
+        for p in &self.problems {
+            write!(f, "\n  {}", p)?;
+        }
+        return Ok(());
+    }
+}
+
+impl Error for MapErrors {}
+
+// Check a map file before it gets turned into a WorldMap, collecting every problem instead of stopping at the first.
+// Anything that passes can be loaded and played without panicking on bad indices
+pub fn validate_map(json: &WorldMapJSON, assets: &MapAssets) -> Vec<MapProblem> {
+    let mut problems = vec![];
+    if json.height == 0 || json.width == 0 {
+        problems.push(MapProblem::EmptyMap);
+    }
+    if !(json.view_distance > 0.0) {
+        problems.push(MapProblem::BadViewDistance);
+    }
+    let num_textures = assets.textures.num_textures() as i32;
+    let grids = [("wall_grid", &json.wall_grid), ("floor_grid", &json.floor_grid), ("ceil_grid", &json.ceil_grid)];
+    for (name, grid) in grids.iter() {
+        if grid.len() != json.height as usize {
+            problems.push(MapProblem::GridHeight { grid: name, expected: json.height, found: grid.len() });
+        }
+        for (row, cells) in grid.iter().enumerate() {
+            if cells.len() != json.width as usize {
+                problems.push(MapProblem::RaggedRow { grid: name, row: row, expected: json.width, found: cells.len() });
+            }
+            // Textures are 1-indexed, 0 means none
+            for (column, tex) in cells.iter().enumerate() {
+                if *tex < 0 || *tex > num_textures {
+                    problems.push(MapProblem::BadTexture { grid: name, row: row, column: column, tex: *tex });
+                }
+            }
+        }
+    }
+
+    if json.player_starts.is_empty() {
+        problems.push(MapProblem::NoPlayerStart);
+    }
+    for (index, start) in json.player_starts.iter().enumerate() {
+        match wall_at(json, start.x, start.y) {
+            None => problems.push(MapProblem::PlayerStartOutOfBounds { index: index }),
+            Some(_) if json.pushwalls.iter().any(|p| in_cell(start.x, start.y, p.x, p.y)) =>
+                problems.push(MapProblem::PlayerStartOnPushWall { index: index }),
+            Some(tex) if tex != 0 => problems.push(MapProblem::PlayerStartInWall { index: index }),
+            Some(_) if json.doors.iter().any(|d| in_cell(start.x, start.y, d.x, d.y)) =>
+                problems.push(MapProblem::PlayerStartOnDoor { index: index }),
+            _ => {},
+        }
+        if start.dir_x == 0.0 && start.dir_y == 0.0 {
+            problems.push(MapProblem::PlayerStartNoDirection { index: index });
+        }
+    }
+
+    // Templates are shared, so each one's only checked (and reported) at the first entity using it
+    let mut checked = HashSet::new();
+    for (index, e) in json.entities.iter().enumerate() {
+        if wall_at(json, e.x, e.y).is_none() {
+            problems.push(MapProblem::EntityOutOfBounds { index: index, name: e.name.clone() });
+        }
+        if !assets.entities.has_entity(&e.name) {
+            problems.push(MapProblem::UnknownEntity { index: index, name: e.name.clone() });
+        } else if checked.insert(e.name.clone()) {
+            check_template(index, &e.name, assets, &mut problems);
+        }
+        if !e.animation.is_empty() && assets.animations.get_animation(&e.animation).is_none() {
+            problems.push(MapProblem::UnknownAnimation { index: index, name: e.name.clone(), animation: e.animation.clone() });
+        }
+    }
+
+    // A locked door needs its key somewhere on the map, otherwise everything behind it can't be reached
+    let keys: HashSet<KeyColour> = json.entities.iter()
+        .filter_map(|e| assets.entities.get_template(&e.name)?.pickup.as_ref())
+        .filter_map(|p| match p.effect {
+            PickupEffect::Key { colour } => Some(colour),
+            _ => None,
+        })
+        .collect();
+    for (index, d) in json.doors.iter().enumerate() {
+        match wall_at(json, d.x as f64, d.y as f64) {
+            None => problems.push(MapProblem::DoorOutOfBounds { index: index }),
+            Some(tex) if tex != 0 => problems.push(MapProblem::DoorOnWall { index: index }),
+            _ => {},
+        }
+        if d.tex < 1 || d.tex > num_textures {
+            problems.push(MapProblem::DoorBadTexture { index: index, tex: d.tex });
+        }
+        if let Some(colour) = d.lock {
+            if !keys.contains(&colour) {
+                problems.push(MapProblem::DoorKeyMissing { index: index, colour: colour });
+            }
+        }
+    }
+
+    for (index, p) in json.pushwalls.iter().enumerate() {
+        match wall_at(json, p.x as f64, p.y as f64) {
+            None => problems.push(MapProblem::PushWallOutOfBounds { index: index }),
+            Some(0) => problems.push(MapProblem::PushWallNotOnWall { index: index }),
+            _ => {},
+        }
+    }
+
+    return problems;
+}
+
+// Things an entity's template refers to that would otherwise only fail once it's running,
+// e.g. a misspelt projectile quietly stops the entity ever attacking
+fn check_template(index: usize, name: &str, assets: &MapAssets, problems: &mut Vec<MapProblem>) {
+    check_sprite_frames(index, name, name, assets, problems);
+    let ai = match assets.entities.get_template(name).and_then(|t| t.ai.as_ref()) {
+        Some(ai) if ai.attack == AttackType::Projectile => ai,
+        _ => return,
+    };
+    let projectile = match assets.entities.get_template(&ai.projectile).and_then(|t| t.projectile.as_ref()) {
+        Some(p) if assets.entities.has_entity(&ai.projectile) => p,
+        _ => {
+            problems.push(MapProblem::UnknownProjectile { index: index, name: name.to_string(), projectile: ai.projectile.clone() });
+            return;
+        }
+    };
+    if !projectile.impact_animation.is_empty() && assets.animations.get_animation(&projectile.impact_animation).is_none() {
+        problems.push(MapProblem::UnknownImpactAnimation {
+            index: index,
+            name: name.to_string(),
+            projectile: ai.projectile.clone(),
+            animation: projectile.impact_animation.clone(),
+        });
+    }
+    check_sprite_frames(index, name, &ai.projectile, assets, problems);
+}
+
+// Non-rotating sheets are split into columns x rows frames, so neither can be 0
+fn check_sprite_frames(index: usize, name: &str, tmpl_name: &str, assets: &MapAssets, problems: &mut Vec<MapProblem>) {
+    if let Some(sprite) = assets.entities.get_sprite(tmpl_name) {
+        if !sprite.rotating && (sprite.columns == 0 || sprite.rows == 0) {
+            problems.push(MapProblem::BadSpriteFrames { index: index, name: name.to_string(), sprite: sprite.name.clone() });
+        }
+    }
+}
+
+// Wall texture at a world position, None if it's outside the map (or the grid is too short to hold it).
+// World x runs the opposite way to the JSON columns
+fn wall_at(json: &WorldMapJSON, x: f64, y: f64) -> Option<i32> {
+    if x < 0.0 || y < 0.0 || x >= json.width as f64 || y >= json.height as f64 {
+        return None;
+    }
+    let row = json.wall_grid.get(y as usize)?;
+    let column = json.width as usize - 1 - x as usize;
+    return row.get(column).cloned();
+}
+
+// Whether a world position is inside cell (x, y)
+fn in_cell(pos_x: f64, pos_y: f64, x: u32, y: u32) -> bool {
+    pos_x.floor() == x as f64 && pos_y.floor() == y as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::DoorJSON;
+    use crate::data::EntityJSON;
+    use crate::sprites::SpriteManager;
+
+    // Run a check against the real assets in data/ (loaded without SDL), plus extra sprites and entity
+    // templates given as JSON
+    fn with_assets<F: FnOnce(&MapAssets)>(extra_sprites: &[&str], extra_templates: &[&str], check: F) {
+        let mut textures = TextureManager::new();
+        textures.load().unwrap();
+        let mut sprites = SpriteManager::new();
+        sprites.load().unwrap();
+        for s in extra_sprites {
+            sprites.add_sprite(serde_json::from_str(s).unwrap());
+        }
+        let mut entities = EntityManager::new(&sprites);
+        entities.init().unwrap();
+        for t in extra_templates {
+            entities.add_template(serde_json::from_str(t).unwrap());
+        }
+        let mut animations = AnimationManager::new();
+        animations.init().unwrap();
+        check(&MapAssets { textures: &textures, entities: &entities, animations: &animations });
+    }
+
+    // Walled 4x4 room with one of everything, no problems
+    fn good_map() -> WorldMapJSON {
+        return serde_json::from_str(r#"{
+            "height": 4,
+            "width": 4,
+            "player_starts": [{ "x": 1.5, "y": 1.5, "dir_x": 1.0, "dir_y": 0.0 }],
+            "wall_grid": [[1,1,1,1], [1,0,0,1], [1,0,0,1], [1,1,1,1]],
+            "floor_grid": [[1,1,1,1], [1,1,1,1], [1,1,1,1], [1,1,1,1]],
+            "ceil_grid": [[0,0,0,0], [0,0,0,0], [0,0,0,0], [0,0,0,0]],
+            "entities": [{ "name": "barrel", "x": 2.5, "y": 2.5 }],
+            "doors": [{ "x": 1, "y": 2, "tex": 1 }],
+            "pushwalls": [{ "x": 0, "y": 1 }]
+        }"#).unwrap();
+    }
+
+    fn problems(json: &WorldMapJSON) -> Vec<MapProblem> {
+        return problems_with(json, &[], &[]);
+    }
+
+    fn problems_with(json: &WorldMapJSON, extra_sprites: &[&str], extra_templates: &[&str]) -> Vec<MapProblem> {
+        let mut problems = vec![];
+        with_assets(extra_sprites, extra_templates, |assets| problems = validate_map(json, assets));
+        return problems;
+    }
+
+    // Enemy template that fires the named projectile
+    fn shooter(name: &str, projectile: &str) -> String {
+        return format!(r#"{{
+            "name": "{}",
+            "sprite_name": "05_cacodemon",
+            "ai": {{
+                "sight_range": 8.0, "reaction_time": 0.5, "speed": 1.0, "attack_range": 6.0, "attack_cooldown": 2.0,
+                "attack": "projectile",
+                "projectile": "{}"
+            }}
+        }}"#, name, projectile);
+    }
+
+    fn projectile(name: &str, sprite: &str, impact_animation: &str) -> String {
+        return format!(r#"{{
+            "name": "{}",
+            "sprite_name": "{}",
+            "projectile": {{ "speed": 5.0, "lifetime": 4.0, "damage": 10.0, "radius": 0.1, "impact_animation": "{}" }}
+        }}"#, name, sprite, impact_animation);
+    }
+
+    // Single row sheet with no columns
+    const NO_COLUMNS_SPRITE: &str = r#"{
+        "name": "test_no_columns", "tex_id": "6", "u_scale": 1.0, "v_scale": 1.0, "u_move": 0, "v_move": 0, "columns": 0
+    }"#;
+
+    fn entity(name: &str, x: f64, y: f64) -> EntityJSON {
+        return EntityJSON { name: name.to_string(), x: x, y: y, dir_x: 0.0, dir_y: 0.0, animation: String::new(), tags: vec![] };
+    }
+
+    #[test]
+    fn good_map_has_no_problems() {
+        assert_eq!(problems(&good_map()), vec![]);
+    }
+
+    #[test]
+    fn empty_map() {
+        let mut json = good_map();
+        json.height = 0;
+        json.player_starts.clear();
+        json.entities.clear();
+        json.doors.clear();
+        json.pushwalls.clear();
+        json.wall_grid.clear();
+        json.floor_grid.clear();
+        json.ceil_grid.clear();
+        assert_eq!(problems(&json), vec![MapProblem::EmptyMap, MapProblem::NoPlayerStart]);
+    }
+
+    #[test]
+    fn bad_view_distance() {
+        let mut json = good_map();
+        json.view_distance = 0.0;
+        assert_eq!(problems(&json), vec![MapProblem::BadViewDistance]);
+    }
+
+    #[test]
+    fn grid_height_mismatch() {
+        let mut json = good_map();
+        json.ceil_grid.pop();
+        assert_eq!(problems(&json), vec![MapProblem::GridHeight { grid: "ceil_grid", expected: 4, found: 3 }]);
+    }
+
+    #[test]
+    fn ragged_row() {
+        let mut json = good_map();
+        json.floor_grid[2].push(1);
+        assert_eq!(problems(&json), vec![MapProblem::RaggedRow { grid: "floor_grid", row: 2, expected: 4, found: 5 }]);
+    }
+
+    #[test]
+    fn bad_texture() {
+        let mut json = good_map();
+        json.wall_grid[3][1] = 999;
+        json.ceil_grid[0][2] = -1;
+        assert_eq!(problems(&json), vec![
+            MapProblem::BadTexture { grid: "wall_grid", row: 3, column: 1, tex: 999 },
+            MapProblem::BadTexture { grid: "ceil_grid", row: 0, column: 2, tex: -1 },
+        ]);
+    }
+
+    #[test]
+    fn bad_player_starts() {
+        let mut json = good_map();
+        json.player_starts[0].dir_x = 0.0;
+        let mut outside = json.player_starts[0].clone();
+        outside.x = 4.5;
+        outside.dir_x = 1.0;
+        let mut in_wall = outside.clone();
+        in_wall.x = 3.5;
+        let mut on_pushwall = outside.clone();
+        on_pushwall.x = 0.5;
+        let mut on_door = outside.clone();
+        on_door.x = 1.2;
+        on_door.y = 2.9;
+        json.player_starts.push(outside);
+        json.player_starts.push(in_wall);
+        json.player_starts.push(on_pushwall);
+        json.player_starts.push(on_door);
+        assert_eq!(problems(&json), vec![
+            MapProblem::PlayerStartNoDirection { index: 0 },
+            MapProblem::PlayerStartOutOfBounds { index: 1 },
+            MapProblem::PlayerStartInWall { index: 2 },
+            MapProblem::PlayerStartOnPushWall { index: 3 },
+            MapProblem::PlayerStartOnDoor { index: 4 },
+        ]);
+
+        json.player_starts.clear();
+        assert_eq!(problems(&json), vec![MapProblem::NoPlayerStart]);
+    }
+
+    #[test]
+    fn bad_entities() {
+        let mut json = good_map();
+        json.entities.push(entity("barrel", -0.5, 1.5));
+        json.entities.push(entity("no_such_entity", 1.5, 2.5));
+        let mut animated = entity("soldier", 2.5, 1.5);
+        animated.animation = "no_such_animation".to_string();
+        json.entities.push(animated);
+        assert_eq!(problems(&json), vec![
+            MapProblem::EntityOutOfBounds { index: 1, name: "barrel".to_string() },
+            MapProblem::UnknownEntity { index: 2, name: "no_such_entity".to_string() },
+            MapProblem::UnknownAnimation { index: 3, name: "soldier".to_string(), animation: "no_such_animation".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn bad_doors() {
+        let mut json = good_map();
+        json.doors.push(DoorJSON { x: 4, y: 1, tex: 1, lock: None });
+        json.doors.push(DoorJSON { x: 0, y: 0, tex: 1, lock: None });
+        json.doors.push(DoorJSON { x: 2, y: 2, tex: 0, lock: None });
+        assert_eq!(problems(&json), vec![
+            MapProblem::DoorOutOfBounds { index: 1 },
+            MapProblem::DoorOnWall { index: 2 },
+            MapProblem::DoorBadTexture { index: 3, tex: 0 },
+        ]);
+    }
+
+    #[test]
+    fn locked_door_without_key() {
+        let mut json = good_map();
+        json.doors[0].lock = Some(KeyColour::Red);
+        json.entities.push(entity("key_blue", 2.5, 1.5));
+        assert_eq!(problems(&json), vec![MapProblem::DoorKeyMissing { index: 0, colour: KeyColour::Red }]);
+
+        json.entities.push(entity("key_red", 1.5, 2.5));
+        assert_eq!(problems(&json), vec![]);
+    }
+
+    #[test]
+    fn bad_pushwalls() {
+        let mut json = good_map();
+        json.pushwalls[0].y = 4;
+        json.pushwalls.push(json.pushwalls[0].clone());
+        json.pushwalls[1].x = 2;
+        json.pushwalls[1].y = 1;
+        assert_eq!(problems(&json), vec![
+            MapProblem::PushWallOutOfBounds { index: 0 },
+            MapProblem::PushWallNotOnWall { index: 1 },
+        ]);
+    }
+
+    #[test]
+    fn bad_projectile_names() {
+        let mut json = good_map();
+        json.entities.push(entity("test_misspelt", 1.5, 2.5));
+        json.entities.push(entity("test_misspelt", 2.5, 1.5));
+        json.entities.push(entity("test_not_projectile", 2.5, 1.5));
+        let templates = [shooter("test_misspelt", "fireabll"), shooter("test_not_projectile", "barrel")];
+        let templates: Vec<&str> = templates.iter().map(|t| t.as_str()).collect();
+        // The shared template is only reported once
+        assert_eq!(problems_with(&json, &[], &templates), vec![
+            MapProblem::UnknownProjectile { index: 1, name: "test_misspelt".to_string(), projectile: "fireabll".to_string() },
+            MapProblem::UnknownProjectile { index: 3, name: "test_not_projectile".to_string(), projectile: "barrel".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn bad_impact_animation() {
+        let mut json = good_map();
+        json.entities.push(entity("test_shooter", 1.5, 2.5));
+        let templates = [shooter("test_shooter", "test_bolt"), projectile("test_bolt", "06_fireball", "no_such_animation")];
+        let templates: Vec<&str> = templates.iter().map(|t| t.as_str()).collect();
+        assert_eq!(problems_with(&json, &[], &templates), vec![
+            MapProblem::UnknownImpactAnimation {
+                index: 1,
+                name: "test_shooter".to_string(),
+                projectile: "test_bolt".to_string(),
+                animation: "no_such_animation".to_string(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn zero_sprite_frames() {
+        let mut json = good_map();
+        json.entities.push(entity("test_flat", 1.5, 2.5));
+        json.entities.push(entity("test_shooter", 2.5, 1.5));
+        let templates = [
+            r#"{ "name": "test_flat", "sprite_name": "test_no_columns" }"#.to_string(),
+            shooter("test_shooter", "test_bolt"),
+            projectile("test_bolt", "test_no_columns", ""),
+        ];
+        let templates: Vec<&str> = templates.iter().map(|t| t.as_str()).collect();
+        assert_eq!(problems_with(&json, &[NO_COLUMNS_SPRITE], &templates), vec![
+            MapProblem::BadSpriteFrames { index: 1, name: "test_flat".to_string(), sprite: "test_no_columns".to_string() },
+            MapProblem::BadSpriteFrames { index: 2, name: "test_shooter".to_string(), sprite: "test_no_columns".to_string() },
+        ]);
+    }
+}